@@ -0,0 +1,576 @@
+use crate::error::{APMError, APMErrorType};
+use crate::packages::{hash_reader, HashingWriter, Package, WriteSeek, HIDDEN_FILE_PATH};
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{copy, sink, Cursor, Read};
+use std::iter;
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+pub const BLOBS_DIR_NAME: &str = "blobs";
+pub const MANIFEST_FORMAT_VERSION: u32 = 2;
+// Entries larger than this are compressed through a temporary file when checking their level.
+const SPOOLED_LEVEL_CHECK_SIZE: usize = 4 * 1024 * 1024;
+
+/// A directory of files named by the SHA-256 of their contents, in `<first two digits>/<hash>`, so
+/// content shared by several packages is only kept once.
+pub struct BlobStore {
+    root: PathBuf,
+}
+
+/// Everything needed to rebuild a package from its blobs, see the README for the format.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Manifest {
+    pub format_version: u32,
+    /// The SHA-256 of the package as it was stored.
+    pub sha256: String,
+    pub size: u64,
+    /// The archive comment, base64 encoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    pub entries: Vec<ManifestEntry>,
+    /// How the `/hidden` checksum entry was written, its contents are recalculated when the
+    /// package is rebuilt.
+    pub checksum_entry: Option<EntryOptions>,
+    /// The contents of the `/hidden` entry as it was stored, which a rebuilt package only has when
+    /// it is identical. Manifests written before it was recorded leave it out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ManifestEntry {
+    pub name: String,
+    /// The SHA-256 of the entry's contents, directories have none.
+    pub blob: Option<String>,
+    pub size: u64,
+    #[serde(flatten)]
+    pub options: EntryOptions,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct EntryOptions {
+    pub compression: String,
+    /// The modification time in MS-DOS format, the date in the high 16 bits and the time in the
+    /// low 16 bits, as it is stored in the zip. Manifests written by older versions call it
+    /// `modified`.
+    #[serde(alias = "modified")]
+    pub dos_datetime: u32,
+    pub unix_mode: Option<u32>,
+    /// The deflate level, when it is not the default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+}
+
+impl BlobStore {
+    pub fn new<P: AsRef<Path>>(store_root: P) -> Self {
+        return Self {
+            root: store_root.as_ref().join(BLOBS_DIR_NAME),
+        };
+    }
+
+    pub fn root(&self) -> &Path {
+        return &self.root;
+    }
+
+    /// The path of the blob named `hash`, which must be a SHA-256 in lowercase hex as names are
+    /// read from manifests on disk.
+    pub fn path(&self, hash: &str) -> Result<PathBuf, APMError> {
+        let valid = hash.len() == 64
+            && hash
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+
+        if !valid {
+            return Err(APMErrorType::BlobReadError
+                .into_apm_error(format!("{:?} is not a valid blob name", hash))
+                .with_path(&self.root));
+        }
+
+        return Ok(self.root.join(&hash[..2]).join(hash));
+    }
+
+    pub fn open(&self, hash: &str) -> Result<File, APMError> {
+        let path = self.path(hash)?;
+
+        return File::open(&path)
+            .map_err(|e| APMErrorType::BlobReadError.from_error(e).with_path(&path));
+    }
+
+    /// Writes the contents of `reader` as a blob unless there already is one with the same
+    /// contents, returning its hash and size.
+    pub fn write<R: std::io::Read>(&self, reader: &mut R) -> Result<(String, u64), APMError> {
+        let write_error = |e: std::io::Error| {
+            return APMErrorType::BlobWriteError
+                .from_error(e)
+                .with_path(&self.root);
+        };
+
+        fs::create_dir_all(&self.root).map_err(write_error)?;
+
+        let mut temp = tempfile::Builder::new()
+            .prefix(".blob.")
+            .suffix(".tmp")
+            .tempfile_in(&self.root)
+            .map_err(|e| {
+                APMErrorType::TempFileCreationError
+                    .from_error(e)
+                    .with_path(&self.root)
+            })?;
+        let mut writer = HashingWriter::new(temp.as_file_mut());
+        let size = copy(reader, &mut writer).map_err(write_error)?;
+        let hash = to_hex(&writer.finalize());
+        let dest = self.path(&hash)?;
+
+        if dest.exists() {
+            return Ok((hash, size));
+        }
+
+        temp.as_file().sync_all().map_err(write_error)?;
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(write_error)?;
+        }
+
+        temp.persist(&dest).map_err(|e| write_error(e.error))?;
+
+        return Ok((hash, size));
+    }
+}
+
+impl Manifest {
+    /// Splits `package` into blobs, returning the manifest to rebuild it with.
+    pub fn store(blobs: &BlobStore, package: &mut Package) -> Result<Self, APMError> {
+        let sha256 = to_hex(&hash_reader(&mut package.reader()?)?);
+        let size = package.size()?;
+        let mut archive = ZipArchive::new(package.reader()?)
+            .map_err(|e| APMErrorType::ZIPArchiveOpenError.from_error(e))?;
+        let mut entries = Vec::with_capacity(archive.len());
+        let mut checksum_entry = None;
+        let mut checksum = None;
+        let mut levels = LevelDetection::default();
+        let comment = match archive.comment() {
+            [] => None,
+            c => Some(base64::encode(c)),
+        };
+
+        for i in 0..archive.len() {
+            let mut raw_file = archive
+                .by_index_raw(i)
+                .map_err(|e| APMErrorType::ZIPArchiveReadError.from_error(e))?;
+            let name = raw_file.name().to_string();
+            let mut options = EntryOptions {
+                compression: compression_name(raw_file.compression())
+                    .map_err(|e| e.with_entry(&name))?,
+                dos_datetime: ((raw_file.last_modified().datepart() as u32) << 16)
+                    | raw_file.last_modified().timepart() as u32,
+                unix_mode: raw_file.unix_mode(),
+                level: None,
+            };
+            let is_dir = raw_file.is_dir();
+
+            // The compressed data is only needed to work out the deflate level.
+            let raw = match raw_file.compression() == CompressionMethod::Deflated && !is_dir {
+                true => Some(RawEntry::read(&mut raw_file).map_err(|e| e.with_entry(&name))?),
+                false => None,
+            };
+
+            drop(raw_file);
+
+            let mut f = archive
+                .by_index(i)
+                .map_err(|e| APMErrorType::ZIPArchiveReadError.from_error(e))?;
+
+            if name == HIDDEN_FILE_PATH {
+                let mut contents = Vec::new();
+
+                f.read_to_end(&mut contents).map_err(|e| {
+                    APMErrorType::ZIPFileReadError
+                        .from_error(e)
+                        .with_entry(&name)
+                })?;
+
+                if let Some(raw) = &raw {
+                    options.level = levels.detect(raw, || Ok(Cursor::new(&contents)))?;
+                }
+
+                checksum_entry = Some(options);
+                checksum = Some(String::from_utf8_lossy(&contents).to_string());
+                continue;
+            }
+
+            let (blob, size) = match is_dir {
+                true => (None, 0),
+                false => {
+                    let (hash, size) = blobs.write(&mut f).map_err(|e| e.with_entry(&name))?;
+
+                    if let Some(raw) = &raw {
+                        options.level = levels.detect(raw, || blobs.open(&hash))?;
+                    }
+
+                    (Some(hash), size)
+                }
+            };
+
+            entries.push(ManifestEntry {
+                name,
+                blob,
+                size,
+                options,
+            });
+        }
+
+        return Ok(Self {
+            format_version: MANIFEST_FORMAT_VERSION,
+            sha256,
+            size,
+            comment,
+            entries,
+            checksum_entry,
+            checksum,
+        });
+    }
+
+    /// The hashes of every blob the manifest uses.
+    pub fn blobs(&self) -> impl Iterator<Item = &str> {
+        return self.entries.iter().filter_map(|e| e.blob.as_deref());
+    }
+
+    /// Rebuilds the package from its blobs, recalculating the checksum if it had one. Packages made
+    /// by apm with fixed timestamps come out byte for byte the same, see `is_identical`.
+    pub fn assemble(&self, blobs: &BlobStore) -> Result<Package, APMError> {
+        let (mut package, _) = Package::from_writer(|output| self.write_entries(blobs, output))?;
+
+        if let Some(options) = &self.checksum_entry {
+            package.add_checksum_with_options(options.file_options()?)?;
+        }
+
+        return Ok(package);
+    }
+
+    /// Whether `package` has exactly the bytes which were stored.
+    pub fn is_identical(&self, package: &Package) -> Result<bool, APMError> {
+        return Ok(package.size()? == self.size
+            && to_hex(&hash_reader(&mut package.reader()?)?) == self.sha256);
+    }
+
+    fn write_entries(&self, blobs: &BlobStore, output: &mut dyn WriteSeek) -> Result<(), APMError> {
+        let mut zip_writer = ZipWriter::new(output);
+
+        if let Some(comment) = &self.comment {
+            zip_writer.set_raw_comment(base64::decode(comment).map_err(|e| {
+                APMErrorType::ManifestReadError
+                    .into_apm_error(format!("The archive comment is not valid base64: {}", e))
+            })?);
+        }
+
+        for entry in &self.entries {
+            let options = entry.options.file_options()?;
+            let name = entry.name.as_str();
+
+            let blob = match &entry.blob {
+                Some(blob) => blob,
+                None => {
+                    zip_writer.add_directory(name, options).map_err(|e| {
+                        APMErrorType::ZIPAddDirectoryError
+                            .from_error(e)
+                            .with_entry(name)
+                    })?;
+
+                    continue;
+                }
+            };
+
+            zip_writer.start_file(name, options).map_err(|e| {
+                APMErrorType::ZIPStartFileError
+                    .from_error(e)
+                    .with_entry(name)
+            })?;
+
+            copy(&mut blobs.open(blob)?, &mut zip_writer).map_err(|e| {
+                APMErrorType::ZIPFileWriteError
+                    .from_error(e)
+                    .with_entry(name)
+            })?;
+        }
+
+        zip_writer
+            .finish()
+            .map_err(|e| APMErrorType::ZIPFinishError.from_error(e))?;
+
+        return Ok(());
+    }
+}
+
+impl EntryOptions {
+    fn file_options(&self) -> Result<FileOptions, APMError> {
+        let modified =
+            DateTime::from_msdos((self.dos_datetime >> 16) as u16, self.dos_datetime as u16);
+        let mut options = FileOptions::default()
+            .compression_method(compression_method(&self.compression)?)
+            .compression_level(self.level)
+            .last_modified_time(modified);
+
+        if let Some(mode) = self.unix_mode {
+            options = options.unix_permissions(mode);
+        }
+
+        return Ok(options);
+    }
+}
+
+// The size and hash of an entry's compressed data.
+struct RawEntry {
+    compressed_size: u64,
+    sha256: [u8; 32],
+}
+
+impl RawEntry {
+    fn read<R: Read>(raw: &mut R) -> Result<Self, APMError> {
+        let mut writer = HashingWriter::new(sink());
+        let compressed_size =
+            copy(raw, &mut writer).map_err(|e| APMErrorType::ZIPFileReadError.from_error(e))?;
+
+        return Ok(Self {
+            compressed_size,
+            sha256: writer.finalize(),
+        });
+    }
+}
+
+// Works out the deflate level of entries by compressing their contents again, so packages made
+// with a level other than the default are rebuilt byte for byte. Once an entry matches no level, as
+// with packages compressed by other programs, the rest are not checked.
+#[derive(Default)]
+struct LevelDetection {
+    last: Option<i32>,
+    gave_up: bool,
+}
+
+impl LevelDetection {
+    fn detect<R, F>(&mut self, raw: &RawEntry, open: F) -> Result<Option<i32>, APMError>
+    where
+        R: Read,
+        F: Fn() -> Result<R, APMError>,
+    {
+        if self.gave_up {
+            return Ok(None);
+        }
+
+        // The default is level 6, after it the level of the previous entry is the likeliest.
+        let last = self.last;
+        let candidates = iter::once(None)
+            .chain(last.map(Some))
+            .chain((0..=9).filter(|l| *l != 6 && Some(*l) != last).map(Some));
+
+        for level in candidates {
+            if deflates_to(&mut open()?, level, raw)? {
+                self.last = level.or(self.last);
+
+                return Ok(level);
+            }
+        }
+
+        self.gave_up = true;
+
+        return Ok(None);
+    }
+}
+
+fn deflates_to<R: Read>(
+    contents: &mut R,
+    level: Option<i32>,
+    raw: &RawEntry,
+) -> Result<bool, APMError> {
+    let mut temp = tempfile::spooled_tempfile(SPOOLED_LEVEL_CHECK_SIZE);
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .compression_level(level);
+
+    {
+        let mut zip_writer = ZipWriter::new(&mut temp);
+
+        zip_writer
+            .start_file("entry", options)
+            .map_err(|e| APMErrorType::ZIPStartFileError.from_error(e))?;
+        copy(contents, &mut zip_writer)
+            .map_err(|e| APMErrorType::ZIPFileWriteError.from_error(e))?;
+        zip_writer
+            .finish()
+            .map_err(|e| APMErrorType::ZIPFinishError.from_error(e))?;
+    }
+
+    let mut archive =
+        ZipArchive::new(&mut temp).map_err(|e| APMErrorType::ZIPArchiveOpenError.from_error(e))?;
+    let mut f = archive
+        .by_index_raw(0)
+        .map_err(|e| APMErrorType::ZIPArchiveReadError.from_error(e))?;
+
+    if f.compressed_size() != raw.compressed_size {
+        return Ok(false);
+    }
+
+    return Ok(RawEntry::read(&mut f)?.sha256 == raw.sha256);
+}
+
+fn compression_name(method: CompressionMethod) -> Result<String, APMError> {
+    let name = match method {
+        CompressionMethod::Stored => "stored",
+        CompressionMethod::Deflated => "deflated",
+        CompressionMethod::Bzip2 => "bzip2",
+        CompressionMethod::Zstd => "zstd",
+        _ => {
+            return Err(
+                APMErrorType::UnsupportedCompressionError.into_apm_error(format!(
+                    "Entries compressed with {} cannot be stored",
+                    method
+                )),
+            )
+        }
+    };
+
+    return Ok(name.to_string());
+}
+
+fn compression_method(name: &str) -> Result<CompressionMethod, APMError> {
+    return match name {
+        "stored" => Ok(CompressionMethod::Stored),
+        "deflated" => Ok(CompressionMethod::Deflated),
+        "bzip2" => Ok(CompressionMethod::Bzip2),
+        "zstd" => Ok(CompressionMethod::Zstd),
+        _ => Err(APMErrorType::ManifestReadError
+            .into_apm_error(format!("Unknown compression method {}", name))),
+    };
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zip_manipulation::{CompressOptions, CompressionSettings};
+    const SAMPLE_DIR: &str = "test_files/test_dir";
+    const SAMPLE_HIDDEN_ZIP: &str = "test_files/hidden.zip";
+
+    #[test]
+    fn test_reproducible_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = BlobStore::new(dir.path());
+        let options = CompressOptions {
+            reproducible: true,
+            timestamp: Some(1650000000),
+            ..Default::default()
+        };
+        let (mut package, _) = Package::from_directory(SAMPLE_DIR, &options).unwrap();
+        package
+            .add_checksum_with_options(options.file_options().unwrap())
+            .unwrap();
+
+        let manifest = Manifest::store(&blobs, &mut package).unwrap();
+        assert!(manifest.checksum_entry.is_some());
+        assert_eq!(
+            manifest.checksum.as_deref(),
+            package.stored_checksum().unwrap()
+        );
+        assert!(manifest.blobs().all(|b| blobs.path(b).unwrap().is_file()));
+
+        // Times are kept as they are in the zip rather than as Unix timestamps, older manifests
+        // call them `modified`.
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert!(json.contains("\"dos_datetime\":") && !json.contains("\"modified\":"));
+        assert_eq!(
+            serde_json::from_str::<Manifest>(&json.replace("dos_datetime", "modified")).unwrap(),
+            manifest
+        );
+
+        let mut assembled = manifest.assemble(&blobs).unwrap();
+        assert!(manifest.is_identical(&assembled).unwrap());
+        assert_eq!(
+            assembled.stored_checksum().unwrap(),
+            package.stored_checksum().unwrap()
+        );
+
+        // Storing the same contents again adds no blobs.
+        let count = || walkdir::WalkDir::new(blobs.root()).into_iter().count();
+        let before = count();
+        Manifest::store(&blobs, &mut package).unwrap();
+        assert_eq!(count(), before);
+    }
+
+    #[test]
+    fn test_compression_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = BlobStore::new(dir.path().join("store"));
+        let source = dir.path().join("source");
+        // Tiny files deflate to the same bytes at every level.
+        let text: String = (0..20000)
+            .map(|i| format!("{} {}\n", i, i * i % 977))
+            .collect();
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("large.txt"), text).unwrap();
+
+        let options = CompressOptions {
+            reproducible: true,
+            compression: CompressionSettings {
+                level: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut package, _) = Package::from_directory(source.to_str().unwrap(), &options).unwrap();
+
+        let manifest = Manifest::store(&blobs, &mut package).unwrap();
+        assert_eq!(manifest.entries[0].options.level, Some(1));
+        assert!(manifest
+            .is_identical(&manifest.assemble(&blobs).unwrap())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_invalid_blob_names() {
+        let blobs = BlobStore::new("store");
+        let hash = to_hex(&[0xab; 32]);
+
+        assert!(blobs.path(&hash).unwrap().starts_with("store/blobs/ab"));
+
+        for name in [
+            "",
+            "é",
+            "../../../etc/passwd",
+            &hash.to_uppercase(),
+            &hash[..63],
+        ] {
+            assert_eq!(
+                blobs.path(name).unwrap_err().error_type(),
+                APMErrorType::BlobReadError
+            );
+        }
+    }
+
+    #[test]
+    fn test_recalculates_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = BlobStore::new(dir.path());
+        let mut package = Package::open(SAMPLE_HIDDEN_ZIP).unwrap();
+
+        let manifest = Manifest::store(&blobs, &mut package).unwrap();
+        let mut assembled = manifest.assemble(&blobs).unwrap();
+
+        assert_eq!(
+            assembled
+                .contents()
+                .unwrap()
+                .file_names()
+                .collect::<Vec<_>>(),
+            package.contents().unwrap().file_names().collect::<Vec<_>>()
+        );
+        assert!(matches!(
+            assembled.verify().unwrap(),
+            crate::packages::ChecksumStatus::Valid(_)
+        ));
+    }
+}
@@ -0,0 +1,96 @@
+/// Splits seconds since the Unix epoch into (year, month, day, hour, minute, second) in UTC.
+pub fn civil_from_epoch(secs: u64) -> (i64, u8, u8, u8, u8, u8) {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Convert days since the epoch into a civil date (Howard Hinnant's algorithm).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return (
+        year,
+        month as u8,
+        day as u8,
+        (rem / 3600) as u8,
+        ((rem % 3600) / 60) as u8,
+        (rem % 60) as u8,
+    );
+}
+
+/// Converts a date in UTC into seconds since the Unix epoch at the start of that day, `None` when
+/// the date does not exist or is before the epoch.
+pub fn epoch_from_civil(year: i64, month: u8, day: u8) -> Option<u64> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // The inverse of civil_from_epoch.
+    let y = if month <= 2 { year - 1 } else { year };
+    let m = month as i64;
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    if days < 0 {
+        return None;
+    }
+
+    let secs = days as u64 * 86400;
+
+    // Days past the end of the month roll over into the next one.
+    if civil_from_epoch(secs).2 != day {
+        return None;
+    }
+
+    return Some(secs);
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS UTC`.
+pub fn format_timestamp(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = civil_from_epoch(secs);
+
+    return format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, hour, minute, second
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_civil_from_epoch() {
+        assert_eq!(civil_from_epoch(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(civil_from_epoch(951782400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(civil_from_epoch(1709251199), (2024, 2, 29, 23, 59, 59));
+        assert_eq!(civil_from_epoch(4107542400), (2100, 3, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_epoch_from_civil() {
+        assert_eq!(epoch_from_civil(1970, 1, 1), Some(0));
+        assert_eq!(epoch_from_civil(2024, 2, 29), Some(1709164800));
+        assert_eq!(epoch_from_civil(2023, 2, 29), None);
+        assert_eq!(epoch_from_civil(1969, 12, 31), None);
+        assert_eq!(
+            civil_from_epoch(epoch_from_civil(2000, 12, 31).unwrap()).0,
+            2000
+        );
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1650000000), "2022-04-15 05:20:00 UTC");
+    }
+}
@@ -1,4 +1,8 @@
+use std::error::Error;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Copy, Clone, PartialEq, Hash, Debug)]
 pub enum APMErrorType {
@@ -21,18 +25,50 @@ pub enum APMErrorType {
     WalkdirError,
     SymlinkFoundError,
     CannotProcessFileNameError,
+    StoreCreationError,
+    StoreIndexReadError,
+    StoreIndexWriteError,
+    StoreLocationError,
+    InvalidPackageNameError,
+    PackageNotFoundError,
+    PackageRemoveError,
+    JSONSerializeError,
+    TempFileCreationError,
+    InvalidTimestampError,
+    DirectoryCreationError,
+    FileExistsError,
+    UnsafeEntryPathError,
+    ChecksumExistsError,
+    InvalidIgnorePatternError,
+    UnsupportedCompressionError,
+    InvalidVersionError,
+    MetadataReadError,
+    MetadataWriteError,
+    InvalidMetadataError,
+    BlobReadError,
+    BlobWriteError,
+    ManifestReadError,
+    ManifestWriteError,
 }
 
-#[derive(Clone, PartialEq, Hash, Debug)]
+#[derive(Clone, Debug)]
 pub struct APMError {
     tp: APMErrorType,
     msg: String,
+    path: Option<PathBuf>,
+    entry: Option<String>,
+    source: Option<Arc<dyn Error + Send + Sync>>,
 }
 
 impl APMErrorType {
     pub fn into_apm_error(self, m: String) -> APMError {
         return APMError::new(self, m);
     }
+
+    /// Wraps `e`, keeping it as the source of the new error.
+    pub fn from_error<E: Error + Send + Sync + 'static>(self, e: E) -> APMError {
+        return APMError::new(self, e.to_string()).with_source(e);
+    }
 }
 
 impl fmt::Display for APMErrorType {
@@ -59,6 +95,30 @@ impl fmt::Display for APMErrorType {
             APMErrorType::WalkdirError => "Walk Directory Error",
             APMErrorType::SymlinkFoundError => "Symlink Error",
             APMErrorType::CannotProcessFileNameError => "Cannot Process File Name Error",
+            APMErrorType::StoreCreationError => "Package Store Creation Error",
+            APMErrorType::StoreIndexReadError => "Package Store Index Read Error",
+            APMErrorType::StoreIndexWriteError => "Package Store Index Write Error",
+            APMErrorType::StoreLocationError => "Package Store Location Error",
+            APMErrorType::InvalidPackageNameError => "Invalid Package Name Error",
+            APMErrorType::PackageNotFoundError => "Package Not Found Error",
+            APMErrorType::PackageRemoveError => "Package Remove Error",
+            APMErrorType::JSONSerializeError => "JSON Serialize Error",
+            APMErrorType::TempFileCreationError => "Temporary File Creation Error",
+            APMErrorType::InvalidTimestampError => "Invalid Timestamp Error",
+            APMErrorType::DirectoryCreationError => "Directory Creation Error",
+            APMErrorType::FileExistsError => "File Exists Error",
+            APMErrorType::UnsafeEntryPathError => "Unsafe Entry Path Error",
+            APMErrorType::ChecksumExistsError => "Checksum Exists Error",
+            APMErrorType::InvalidIgnorePatternError => "Invalid Ignore Pattern Error",
+            APMErrorType::UnsupportedCompressionError => "Unsupported Compression Error",
+            APMErrorType::InvalidVersionError => "Invalid Version Error",
+            APMErrorType::MetadataReadError => "Metadata Read Error",
+            APMErrorType::MetadataWriteError => "Metadata Write Error",
+            APMErrorType::InvalidMetadataError => "Invalid Metadata Error",
+            APMErrorType::BlobReadError => "Blob Read Error",
+            APMErrorType::BlobWriteError => "Blob Write Error",
+            APMErrorType::ManifestReadError => "Manifest Read Error",
+            APMErrorType::ManifestWriteError => "Manifest Write Error",
         };
 
         return write!(f, "{}", s);
@@ -67,14 +127,113 @@ impl fmt::Display for APMErrorType {
 
 impl APMError {
     pub fn new(tp: APMErrorType, msg: String) -> Self {
-        return Self { tp, msg };
+        return Self {
+            tp,
+            msg,
+            path: None,
+            entry: None,
+            source: None,
+        };
+    }
+
+    /// Records the file the error relates to.
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        return self;
+    }
+
+    /// Records the zip entry the error relates to.
+    pub fn with_entry(mut self, entry: &str) -> Self {
+        self.entry = Some(entry.to_string());
+        return self;
+    }
+
+    pub fn with_source<E: Error + Send + Sync + 'static>(mut self, source: E) -> Self {
+        self.source = Some(Arc::new(source));
+        return self;
     }
 
-    pub fn error_type(&self) -> String {
-        return self.tp.to_string();
+    pub fn error_type(&self) -> APMErrorType {
+        return self.tp;
     }
 
     pub fn description(&self) -> &str {
         return &self.msg;
     }
+
+    pub fn path(&self) -> Option<&Path> {
+        return self.path.as_deref();
+    }
+
+    pub fn entry(&self) -> Option<&str> {
+        return self.entry.as_deref();
+    }
+}
+
+impl fmt::Display for APMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.tp, self.msg)?;
+
+        if let Some(path) = &self.path {
+            write!(f, " (file: {})", path.display())?;
+        }
+
+        if let Some(entry) = &self.entry {
+            write!(f, " (entry: {})", entry)?;
+        }
+
+        return Ok(());
+    }
+}
+
+impl Error for APMError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        return self.source.as_deref().map(|e| e as &(dyn Error + 'static));
+    }
+}
+
+// The source is left out of comparisons as most errors it can hold are not comparable.
+impl PartialEq for APMError {
+    fn eq(&self, other: &Self) -> bool {
+        return self.tp == other.tp
+            && self.msg == other.msg
+            && self.path == other.path
+            && self.entry == other.entry;
+    }
+}
+
+impl Hash for APMError {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.tp.hash(state);
+        self.msg.hash(state);
+        self.path.hash(state);
+        self.entry.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_source_and_context() {
+        let err = APMErrorType::FileOpenError
+            .from_error(io::Error::new(io::ErrorKind::NotFound, "missing"))
+            .with_path("package.zip")
+            .with_entry("out.txt");
+
+        assert_eq!(err.error_type(), APMErrorType::FileOpenError);
+        assert_eq!(err.path(), Some(Path::new("package.zip")));
+        assert_eq!(
+            err.to_string(),
+            "File Open Error: missing (file: package.zip) (entry: out.txt)"
+        );
+
+        let source = err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+
+        let boxed: Box<dyn Error + Send + Sync> = Box::new(err);
+        assert!(boxed.source().is_some());
+    }
 }
@@ -0,0 +1,348 @@
+use archer_package_manager::error::APMErrorType;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::panic::resume_unwind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use walkdir::WalkDir;
+
+use crate::output::{
+    error_json, CommandError, Output, Report, GENERAL_EXIT_CODE, SUCCESS_EXIT_CODE,
+};
+
+/// The outcome of an operation on one package of a batch.
+pub struct Outcome {
+    pub status: &'static str,
+    pub detail: String,
+    pub exit_code: i32,
+    pub result: Value,
+}
+
+impl Outcome {
+    pub fn new(status: &'static str, detail: String, result: Value) -> Self {
+        return Self {
+            status,
+            detail,
+            exit_code: SUCCESS_EXIT_CODE,
+            result,
+        };
+    }
+
+    pub fn with_exit_code(mut self, exit_code: i32) -> Self {
+        self.exit_code = exit_code;
+        return self;
+    }
+}
+
+/// Whether `paths` can name more than one package, i.e. there are several paths, globs or
+/// directories.
+pub fn is_batch(paths: &[String]) -> bool {
+    return paths.len() > 1 || paths.iter().any(|p| is_glob(p) || Path::new(p).is_dir());
+}
+
+fn is_glob(path: &str) -> bool {
+    return !Path::new(path).exists() && path.contains(['*', '?', '[']);
+}
+
+/// Expands globs and directories into the packages they contain, directories are searched
+/// recursively for `.zip` files. Paths which do not exist are kept so they are reported as failures.
+pub fn expand_paths(paths: &[String]) -> Result<Vec<String>, CommandError> {
+    let mut packages = Vec::new();
+
+    for p in paths {
+        if Path::new(p).is_dir() {
+            for entry in WalkDir::new(p).sort_by_file_name() {
+                let entry =
+                    entry.map_err(|e| APMErrorType::WalkdirError.from_error(e).with_path(p))?;
+
+                if entry.file_type().is_file()
+                    && entry.path().extension().is_some_and(|e| e == "zip")
+                {
+                    packages.push(entry.path().display().to_string());
+                }
+            }
+        } else if is_glob(p) {
+            let matches = glob::glob(p)
+                .map_err(|e| CommandError::Usage(format!("Invalid pattern {}: {}", p, e)))?;
+            let found = packages.len();
+
+            for m in matches {
+                let m = m.map_err(|e| {
+                    let path = e.path().to_path_buf();
+
+                    return APMErrorType::FileReadError.from_error(e).with_path(path);
+                })?;
+
+                if m.is_file() {
+                    packages.push(m.display().to_string());
+                }
+            }
+
+            if packages.len() == found {
+                return Err(CommandError::Usage(format!("No packages match {}", p)));
+            }
+        } else {
+            packages.push(p.clone());
+        }
+    }
+
+    // Paths are compared once resolved so `./a.zip` and `a.zip` are not processed at the same time.
+    let mut seen = HashSet::new();
+    packages.retain(|p| seen.insert(fs::canonicalize(p).unwrap_or_else(|_| PathBuf::from(p))));
+
+    return Ok(packages);
+}
+
+/// Runs `op` over `packages` on a pool of `jobs` worker threads, defaulting to one per CPU, and
+/// reports the outcome of each package.
+pub fn run_batch<F>(
+    out: &Output,
+    packages: &[String],
+    jobs: Option<usize>,
+    op: F,
+) -> Result<Report, CommandError>
+where
+    F: Fn(&str) -> Result<Outcome, CommandError> + Sync,
+{
+    let jobs = match jobs {
+        Some(0) => {
+            return Err(CommandError::Usage(
+                "At least one job is needed".to_string(),
+            ))
+        }
+        Some(jobs) => jobs,
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let next = AtomicUsize::new(0);
+
+    let mut outcomes: Vec<(usize, Result<Outcome, CommandError>)> = thread::scope(|s| {
+        let workers: Vec<_> = (0..jobs.min(packages.len()))
+            .map(|_| {
+                s.spawn(|| {
+                    let mut done = Vec::new();
+
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+
+                        if i >= packages.len() {
+                            return done;
+                        }
+
+                        done.push((i, op(&packages[i])));
+                    }
+                })
+            })
+            .collect();
+
+        return workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap_or_else(|e| resume_unwind(e)))
+            .collect();
+    });
+
+    outcomes.sort_by_key(|(i, _)| *i);
+
+    return Ok(summarize(
+        out,
+        packages,
+        outcomes.into_iter().map(|(_, o)| o).collect(),
+    ));
+}
+
+// Prints a table with a row per package. The batch exits with the code of its failures when they
+// agree and the general failure code when they do not.
+fn summarize(
+    out: &Output,
+    packages: &[String],
+    outcomes: Vec<Result<Outcome, CommandError>>,
+) -> Report {
+    let mut rows = Vec::with_capacity(packages.len());
+    let mut results = Vec::with_capacity(packages.len());
+    let mut failure_codes = HashSet::new();
+
+    for (path, outcome) in packages.iter().zip(outcomes) {
+        let exit_code = match &outcome {
+            Ok(o) => o.exit_code,
+            Err(e) => e.exit_code(),
+        };
+
+        if exit_code != SUCCESS_EXIT_CODE {
+            failure_codes.insert(exit_code);
+        }
+
+        match outcome {
+            Ok(o) => {
+                rows.push((path.as_str(), o.status, o.detail));
+                results.push(json!({
+                    "path": path,
+                    "status": o.status,
+                    "exit_code": exit_code,
+                    "result": o.result,
+                }));
+            }
+            Err(e) => {
+                rows.push((path.as_str(), "error", error_description(&e)));
+                results.push(json!({
+                    "path": path,
+                    "status": "error",
+                    "exit_code": exit_code,
+                    "error": error_json(&e),
+                }));
+            }
+        }
+    }
+
+    let path_width = rows.iter().map(|r| r.0.len()).chain([7]).max().unwrap_or(0);
+    let status_width = rows.iter().map(|r| r.1.len()).chain([6]).max().unwrap_or(0);
+
+    out.line(format!(
+        "{:<pw$}  {:<sw$}  DETAIL",
+        "PACKAGE",
+        "STATUS",
+        pw = path_width,
+        sw = status_width
+    ));
+
+    for (path, status, detail) in &rows {
+        out.line(format!(
+            "{:<pw$}  {:<sw$}  {}",
+            path,
+            status,
+            detail,
+            pw = path_width,
+            sw = status_width
+        ));
+    }
+
+    let failed = results
+        .iter()
+        .filter(|r| r["exit_code"] != SUCCESS_EXIT_CODE)
+        .count();
+    let succeeded = results.len() - failed;
+
+    out.line("");
+    out.line(format!(
+        "{} packages, {} succeeded, {} failed",
+        results.len(),
+        succeeded,
+        failed
+    ));
+
+    let exit_code = match failure_codes.len() {
+        0 => SUCCESS_EXIT_CODE,
+        1 => failure_codes
+            .into_iter()
+            .next()
+            .unwrap_or(GENERAL_EXIT_CODE),
+        _ => GENERAL_EXIT_CODE,
+    };
+
+    return Report::new(json!({
+        "packages": results,
+        "succeeded": succeeded,
+        "failed": failed,
+    }))
+    .with_exit_code(exit_code);
+}
+
+fn error_description(e: &CommandError) -> String {
+    return match e {
+        CommandError::Package(e) => e.description().to_string(),
+        CommandError::Usage(msg) => msg.clone(),
+        CommandError::Aborted => "Aborted".to_string(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{OutputFormat, CHECKSUM_MISMATCH_EXIT_CODE};
+    use archer_package_manager::error::APMErrorType;
+
+    #[test]
+    fn test_expand_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().display().to_string();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+
+        for file in ["a.zip", "b.zip", "sub/c.zip", "notes.txt"] {
+            fs::write(dir.path().join(file), "").unwrap();
+        }
+
+        let expanded = expand_paths(std::slice::from_ref(&root)).unwrap();
+        let names: Vec<_> = expanded
+            .iter()
+            .map(|p| Path::new(p).strip_prefix(&root).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                Path::new("a.zip"),
+                Path::new("b.zip"),
+                &Path::new("sub").join("c.zip")
+            ]
+        );
+
+        // The same file given in different ways is only processed once.
+        let paths = [
+            format!("{}/a.zip", root),
+            format!("{}/./a.zip", root),
+            format!("{}/sub/../a.zip", root),
+            format!("{}/*.zip", root),
+        ];
+        assert_eq!(
+            expand_paths(&paths).unwrap(),
+            [format!("{}/a.zip", root), format!("{}/b.zip", root)]
+        );
+
+        // Missing files are kept to be reported, patterns matching nothing are an error.
+        let missing = format!("{}/missing.zip", root);
+        assert_eq!(
+            expand_paths(std::slice::from_ref(&missing)).unwrap(),
+            [missing]
+        );
+        assert!(matches!(
+            expand_paths(&[format!("{}/*.jar", root)]),
+            Err(CommandError::Usage(_))
+        ));
+
+        assert!(is_batch(std::slice::from_ref(&root)));
+        assert!(is_batch(&[format!("{}/*.zip", root)]));
+        assert!(!is_batch(&[format!("{}/a.zip", root)]));
+    }
+
+    #[test]
+    fn test_batch_exit_codes() {
+        let out = Output::new(OutputFormat::Text);
+        let run = |packages: &[&str]| {
+            let packages: Vec<_> = packages.iter().map(|p| p.to_string()).collect();
+            let report = run_batch(&out, &packages, Some(2), |p| {
+                return match p {
+                    "mismatch" => Ok(Outcome::new("mismatch", String::new(), Value::Null)
+                        .with_exit_code(CHECKSUM_MISMATCH_EXIT_CODE)),
+                    "missing" => Err(APMErrorType::FileOpenError
+                        .into_apm_error("missing".to_string())
+                        .into()),
+                    _ => Ok(Outcome::new("ok", String::new(), Value::Null)),
+                };
+            });
+
+            return out.finish(report);
+        };
+
+        assert_eq!(run(&["a", "b", "c"]), SUCCESS_EXIT_CODE);
+        assert_eq!(
+            run(&["a", "mismatch", "mismatch"]),
+            CHECKSUM_MISMATCH_EXIT_CODE
+        );
+        // Failures with different codes exit with the general failure code.
+        assert_eq!(run(&["mismatch", "a", "missing"]), GENERAL_EXIT_CODE);
+
+        assert!(matches!(
+            run_batch(&out, &["a".to_string()], Some(0), |_| unreachable!()),
+            Err(CommandError::Usage(_))
+        ));
+    }
+}
@@ -1,7 +1,48 @@
-use clap::{Parser, Subcommand};
+use archer_package_manager::datetime::epoch_from_civil;
+use archer_package_manager::store::{PackageDetails, SearchOrder};
+use archer_package_manager::zip_manipulation::{CompressionSettings, SymlinkPolicy};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use zip::CompressionMethod;
+
+use crate::output::OutputFormat;
 
 #[derive(Debug, PartialEq, Parser)]
 pub struct CLIArgs {
+    #[clap(
+        long,
+        global = true,
+        help = "The path to the package store, defaults to $APM_STORE or ~/.apm"
+    )]
+    pub store: Option<String>,
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        default_value = "text",
+        help = "The output format, json prints a single document with the result or error"
+    )]
+    pub format: OutputFormat,
+    #[clap(
+        short = 'y',
+        long,
+        visible_alias = "yes",
+        global = true,
+        conflicts_with = "no-clobber",
+        help = "Overwrite existing files and modify packages in place without asking"
+    )]
+    pub force: bool,
+    #[clap(
+        long = "no-clobber",
+        global = true,
+        help = "Never overwrite existing files"
+    )]
+    pub no_clobber: bool,
+    #[clap(
+        long,
+        global = true,
+        help = "Keep a file that is replaced as <file>.bak"
+    )]
+    pub backup: bool,
     #[clap(subcommand)]
     pub command: Command,
 }
@@ -9,16 +50,64 @@ pub struct CLIArgs {
 #[derive(Debug, PartialEq, Subcommand)]
 pub enum Command {
     #[clap(name = "man")]
-    Manager,
+    #[clap(about = "Manage the local package store")]
+    Manager {
+        #[clap(subcommand)]
+        operation: ManagerOperation,
+    },
     #[clap(name = "mod")]
     #[clap(about = "Modify an existing package")]
     Modifier {
         #[clap(subcommand)]
         operation: ModiferOperation,
     },
-    // #[clap(name = "info")]
-    // #[clap(about = "Report Information about a package's contents")]
-    // Info {},
+    #[clap(name = "info")]
+    #[clap(about = "Report Information about a package's contents")]
+    Info {
+        #[clap(
+            conflicts_with = "path",
+            short,
+            help = "The name of the package as stored in the management DB",
+            required_unless_present = "path"
+        )]
+        name: Option<String>,
+        #[clap(
+            long = "version",
+            help = "The version of the named package or a requirement such as ^1.2, defaults to the latest revision",
+            requires = "name"
+        )]
+        version: Option<String>,
+        #[clap(
+            conflicts_with = "name",
+            short,
+            help = "The path to the archer zip file",
+            required_unless_present = "name"
+        )]
+        path: Option<String>,
+        #[clap(long, help = "Shorthand for --format json")]
+        json: bool,
+    },
+    #[clap(name = "diff")]
+    #[clap(about = "Compare the contents of two packages")]
+    Diff {
+        #[clap(help = "The path to the original package")]
+        old_path: String,
+        #[clap(help = "The path to the changed package")]
+        new_path: String,
+        #[clap(long, help = "Also compare the hidden checksum file")]
+        include_checksum: bool,
+        #[clap(long, help = "Only list the changed entries, without text diffs")]
+        stat: bool,
+        #[clap(
+            short = 'U',
+            long = "context",
+            default_value = "3",
+            help = "The number of unchanged lines shown around each change"
+        )]
+        context_lines: usize,
+        #[clap(long, help = "Shorthand for --format json")]
+        json: bool,
+    },
 }
 
 #[derive(Debug, PartialEq, Subcommand)]
@@ -36,17 +125,32 @@ pub enum ModiferOperation {
             required_unless_present = "path"
         )]
         name: Option<String>,
+        #[clap(
+            long = "version",
+            help = "The version of the named package or a requirement such as ^1.2, defaults to the latest revision",
+            requires = "name"
+        )]
+        version: Option<String>,
         #[clap(
             conflicts_with = "name",
             short,
-            help = "The path to the archer zip file",
+            multiple_values = true,
+            help = "The paths of archer zip files, directories of them or globs",
             required_unless_present = "name"
         )]
-        path: Option<String>,
+        path: Vec<String>,
         #[clap(short, help = "The path to the output zip file")]
         output_path: Option<String>,
         #[clap(short, long, help = "Show verbose output")]
         verbose: bool,
+        #[clap(
+            short,
+            long,
+            help = "The number of packages processed at once, defaults to the number of CPUs"
+        )]
+        jobs: Option<usize>,
+        #[clap(flatten)]
+        compression: CompressionArgs,
     },
     #[clap(
         short_flag = 'a',
@@ -60,17 +164,121 @@ pub enum ModiferOperation {
             help = "The name of the package as stored in the management DB"
         )]
         name: Option<String>,
-        #[clap(short, help = "Remove the checksum if present in the zip file")]
-        remove_checksum: bool,
+        #[clap(
+            long = "version",
+            help = "The version of the named package or a requirement such as ^1.2, defaults to the latest revision",
+            requires = "name"
+        )]
+        version: Option<String>,
         #[clap(
             short,
-            help = "The path to the archer zip file",
+            long,
+            help = "Replace the checksum if the package already has one instead of failing"
+        )]
+        replace: bool,
+        #[clap(
+            short,
+            multiple_values = true,
+            help = "The paths of archer zip files, directories of them or globs",
             required_unless_present = "name",
             conflicts_with = "name"
         )]
-        path: Option<String>,
+        path: Vec<String>,
         #[clap(short, help = "The path to the output zip file")]
         output_path: Option<String>,
+        #[clap(
+            short,
+            long,
+            help = "The number of packages processed at once, defaults to the number of CPUs"
+        )]
+        jobs: Option<usize>,
+        #[clap(flatten)]
+        compression: CompressionArgs,
+    },
+    #[clap(
+        short_flag = 'v',
+        long_flag = "verify",
+        about = "Check that a package's checksum matches its contents"
+    )]
+    Verify {
+        #[clap(
+            conflicts_with = "path",
+            short,
+            help = "The name of the package as stored in the management DB",
+            required_unless_present = "path"
+        )]
+        name: Option<String>,
+        #[clap(
+            long = "version",
+            help = "The version of the named package or a requirement such as ^1.2, defaults to the latest revision",
+            requires = "name"
+        )]
+        version: Option<String>,
+        #[clap(
+            conflicts_with = "name",
+            short,
+            multiple_values = true,
+            help = "The paths of archer zip files, directories of them or globs",
+            required_unless_present = "name"
+        )]
+        path: Vec<String>,
+        #[clap(
+            short,
+            long,
+            help = "The number of packages processed at once, defaults to the number of CPUs"
+        )]
+        jobs: Option<usize>,
+    },
+    #[clap(
+        short_flag = 'x',
+        long_flag = "extract",
+        about = "Unpack a package into a directory"
+    )]
+    Extract {
+        #[clap(
+            conflicts_with = "path",
+            short,
+            help = "The name of the package as stored in the management DB",
+            required_unless_present = "path"
+        )]
+        name: Option<String>,
+        #[clap(
+            long = "version",
+            help = "The version of the named package or a requirement such as ^1.2, defaults to the latest revision",
+            requires = "name"
+        )]
+        version: Option<String>,
+        #[clap(
+            conflicts_with = "name",
+            short,
+            help = "The path to the archer zip file",
+            required_unless_present = "name"
+        )]
+        path: Option<String>,
+        #[clap(
+            short = 'o',
+            long = "output",
+            help = "The directory to extract into, defaults to the package name"
+        )]
+        output_directory: Option<String>,
+        #[clap(
+            long = "checksum-file",
+            help = "Write the package's checksum to this file, failing if the package has none"
+        )]
+        checksum_file: Option<String>,
+        #[clap(
+            long = "overwrite",
+            help = "Replace files which already exist",
+            conflicts_with = "skip-existing"
+        )]
+        overwrite: bool,
+        #[clap(
+            long = "skip-existing",
+            help = "Leave files which already exist untouched"
+        )]
+        skip_existing: bool,
+        #[clap(short, long, help = "Show verbose output")]
+        verbose: bool,
     },
     #[clap(
         short_flag = 'm',
@@ -100,7 +308,353 @@ pub enum ModiferOperation {
             help = "Specify the output path for the package"
         )]
         output_path: Option<String>,
+        #[clap(
+            long = "reproducible",
+            help = "Build the same bytes for the same directory, using fixed timestamps and permissions"
+        )]
+        reproducible: bool,
+        #[clap(
+            long = "timestamp",
+            help = "Seconds since the Unix epoch to give every entry, defaults to SOURCE_DATE_EPOCH",
+            requires = "reproducible"
+        )]
+        timestamp: Option<u64>,
+        #[clap(
+            long = "exclude",
+            value_name = "PATTERN",
+            help = "Leave out paths matching a .gitignore style pattern, can be repeated"
+        )]
+        exclude: Vec<String>,
+        #[clap(
+            long = "include",
+            value_name = "PATTERN",
+            help = "Keep paths matching a pattern even if .apmignore or --exclude leaves them out"
+        )]
+        include: Vec<String>,
+        #[clap(
+            long,
+            value_enum,
+            default_value = "error",
+            help = "What to do with symlinks: fail, add the files they point to or leave them out"
+        )]
+        symlinks: SymlinkMode,
+        #[clap(flatten)]
+        compression: CompressionArgs,
+        #[clap(flatten)]
+        metadata: MetadataArgs,
         #[clap(short, long, help = "Show verbose output")]
         verbose: bool,
     },
 }
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum ManagerOperation {
+    #[clap(about = "Add a package file to the store")]
+    Add {
+        #[clap(short, help = "The name to store the package under")]
+        name: String,
+        #[clap(short, help = "The path to the archer zip file")]
+        path: String,
+        #[clap(long = "version", help = "Specify the version of the package")]
+        version: Option<String>,
+        #[clap(flatten)]
+        metadata: MetadataArgs,
+    },
+    #[clap(about = "List the packages in the store")]
+    List {
+        #[clap(short, long, help = "Show every revision of each package")]
+        verbose: bool,
+    },
+    #[clap(about = "Remove a package from the store")]
+    Remove {
+        #[clap(help = "The name of the package")]
+        name: String,
+        #[clap(
+            long = "version",
+            help = "Only remove the revisions with this version or matching a requirement such as ^1.2"
+        )]
+        version: Option<String>,
+    },
+    #[clap(about = "Show the details of a stored package")]
+    Show {
+        #[clap(help = "The name of the package")]
+        name: String,
+    },
+    #[clap(about = "List the versions of a stored package")]
+    Versions {
+        #[clap(help = "The name of the package")]
+        name: String,
+    },
+    #[clap(about = "Copy a stored package out of the store")]
+    Get {
+        #[clap(help = "The package, optionally with a version or requirement, e.g. name@^1.2")]
+        package: String,
+        #[clap(
+            short,
+            help = "The output path, defaults to <name>-<version>.zip in the current directory"
+        )]
+        output: Option<String>,
+    },
+    #[clap(about = "Choose the revision used when no version is given")]
+    SetLatest {
+        #[clap(help = "The package and version, e.g. name@1.2")]
+        package: String,
+    },
+    #[clap(about = "Make the version before the latest one the latest")]
+    Rollback {
+        #[clap(help = "The name of the package")]
+        name: String,
+    },
+    #[clap(about = "Show or edit the metadata of a stored package")]
+    Meta {
+        #[clap(help = "The package, optionally with a version or requirement, e.g. name@1.2")]
+        package: String,
+        #[clap(flatten)]
+        metadata: MetadataArgs,
+        #[clap(
+            long = "remove-tag",
+            value_name = "TAG",
+            help = "Remove a tag, can be repeated"
+        )]
+        remove_tags: Vec<String>,
+    },
+    #[clap(about = "Search the packages in the store")]
+    Search {
+        #[clap(help = "Only show packages whose name contains this, ignoring case")]
+        name: Option<String>,
+        #[clap(
+            long = "tag",
+            value_name = "TAG",
+            help = "Only show packages with this tag, can be repeated"
+        )]
+        tags: Vec<String>,
+        #[clap(
+            long = "version",
+            help = "Only show versions matching a version or requirement such as ^1.2"
+        )]
+        version: Option<String>,
+        #[clap(
+            long,
+            value_name = "YYYY-MM-DD",
+            value_parser = parse_date,
+            help = "Only show packages added on or after this date (UTC)"
+        )]
+        since: Option<u64>,
+        #[clap(
+            long,
+            value_name = "YYYY-MM-DD",
+            value_parser = parse_date,
+            help = "Only show packages added on or before this date (UTC)"
+        )]
+        until: Option<u64>,
+        #[clap(
+            long = "archer-version",
+            help = "Only show packages for this Archer version, 6.9 also matches 6.9.1"
+        )]
+        archer_version: Option<String>,
+        #[clap(long, help = "Only show packages with this checksum")]
+        checksum: Option<String>,
+        #[clap(
+            short,
+            long,
+            help = "Show every matching revision rather than one per package"
+        )]
+        all: bool,
+        #[clap(
+            long,
+            value_enum,
+            default_value = "name",
+            help = "How to sort the results"
+        )]
+        sort: SortMode,
+        #[clap(short, long, help = "Reverse the order of the results")]
+        reverse: bool,
+    },
+    #[clap(about = "Remove unused blobs and temporary files from the store")]
+    Gc {
+        #[clap(long = "dry-run", help = "Only list what would be removed")]
+        dry_run: bool,
+        #[clap(
+            long = "min-age",
+            value_name = "SECONDS",
+            default_value = "3600",
+            help = "Keep files changed more recently than this, they may belong to a command which is still running"
+        )]
+        min_age: u64,
+        #[clap(short, long, help = "List every file which is removed")]
+        verbose: bool,
+    },
+    #[clap(about = "Check every package in the store for corruption")]
+    Fsck {
+        #[clap(
+            long,
+            help = "Move broken revisions and corrupt blobs to the quarantine directory and remove them from the index"
+        )]
+        quarantine: bool,
+    },
+}
+
+#[derive(ValueEnum, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SymlinkMode {
+    Error,
+    Follow,
+    Skip,
+}
+
+impl From<SymlinkMode> for SymlinkPolicy {
+    fn from(mode: SymlinkMode) -> Self {
+        return match mode {
+            SymlinkMode::Error => SymlinkPolicy::Error,
+            SymlinkMode::Follow => SymlinkPolicy::Follow,
+            SymlinkMode::Skip => SymlinkPolicy::Skip,
+        };
+    }
+}
+
+#[derive(Debug, PartialEq, Args)]
+pub struct CompressionArgs {
+    #[clap(
+        long,
+        value_enum,
+        help = "The compression method, Archer can only import stored and deflate"
+    )]
+    pub compression: Option<CompressionMode>,
+    #[clap(long, help = "The compression level, 0 to 9 for deflate")]
+    pub level: Option<i32>,
+    #[clap(
+        long = "compress-ext",
+        value_name = "EXT=METHOD",
+        value_parser = parse_extension_method,
+        help = "The compression method for files with an extension, e.g. png=stored, can be repeated"
+    )]
+    pub extension_methods: Vec<(String, CompressionMode)>,
+}
+
+#[derive(ValueEnum, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SortMode {
+    Name,
+    Version,
+    Created,
+    Size,
+}
+
+impl From<SortMode> for SearchOrder {
+    fn from(mode: SortMode) -> Self {
+        return match mode {
+            SortMode::Name => SearchOrder::Name,
+            SortMode::Version => SearchOrder::Version,
+            SortMode::Created => SearchOrder::Created,
+            SortMode::Size => SearchOrder::Size,
+        };
+    }
+}
+
+#[derive(Debug, PartialEq, Args)]
+pub struct MetadataArgs {
+    #[clap(long, help = "A description of the package, an empty value removes it")]
+    pub description: Option<String>,
+    #[clap(long, help = "The author of the package, an empty value removes it")]
+    pub author: Option<String>,
+    #[clap(
+        long = "archer-version",
+        help = "The version of Archer the package is for, an empty value removes it"
+    )]
+    pub archer_version: Option<String>,
+    #[clap(
+        long = "tag",
+        value_name = "TAG",
+        help = "Tag the package, can be repeated"
+    )]
+    pub tags: Vec<String>,
+}
+
+impl MetadataArgs {
+    pub fn is_empty(&self) -> bool {
+        return self.description.is_none()
+            && self.author.is_none()
+            && self.archer_version.is_none()
+            && self.tags.is_empty();
+    }
+
+    /// Sets the details given on the command line, adding to the existing tags.
+    pub fn apply(&self, details: &mut PackageDetails) {
+        if let Some(description) = &self.description {
+            details.description = Some(description.clone());
+        }
+
+        if let Some(author) = &self.author {
+            details.author = Some(author.clone());
+        }
+
+        if let Some(archer_version) = &self.archer_version {
+            details.archer_version = Some(archer_version.clone());
+        }
+
+        details.tags.extend(self.tags.iter().cloned());
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CompressionMode {
+    Stored,
+    Deflate,
+    Bzip2,
+    Zstd,
+}
+
+impl CompressionArgs {
+    /// The settings given on the command line, `None` if there were none.
+    pub fn settings(&self) -> Option<CompressionSettings> {
+        if self.compression.is_none() && self.level.is_none() && self.extension_methods.is_empty() {
+            return None;
+        }
+
+        return Some(CompressionSettings {
+            method: self.compression.map(CompressionMethod::from),
+            level: self.level,
+            extension_methods: self
+                .extension_methods
+                .iter()
+                .map(|(e, m)| (e.clone(), CompressionMethod::from(*m)))
+                .collect(),
+        });
+    }
+}
+
+impl From<CompressionMode> for CompressionMethod {
+    fn from(mode: CompressionMode) -> Self {
+        return match mode {
+            CompressionMode::Stored => CompressionMethod::Stored,
+            CompressionMode::Deflate => CompressionMethod::Deflated,
+            CompressionMode::Bzip2 => CompressionMethod::Bzip2,
+            CompressionMode::Zstd => CompressionMethod::Zstd,
+        };
+    }
+}
+
+fn parse_extension_method(s: &str) -> Result<(String, CompressionMode), String> {
+    let (extension, method) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected EXT=METHOD but found {}", s))?;
+
+    return Ok((
+        extension.to_string(),
+        CompressionMode::from_str(method, true)?,
+    ));
+}
+
+// Parses a YYYY-MM-DD date into seconds since the Unix epoch at the start of the day in UTC.
+fn parse_date(s: &str) -> Result<u64, String> {
+    let parts: Vec<&str> = s.split('-').collect();
+    let invalid = || format!("expected a date such as 2024-01-31 but found {}", s);
+
+    if parts.len() != 3 {
+        return Err(invalid());
+    }
+
+    let year = parts[0].parse::<i64>().map_err(|_| invalid())?;
+    let month = parts[1].parse::<u8>().map_err(|_| invalid())?;
+    let day = parts[2].parse::<u8>().map_err(|_| invalid())?;
+
+    return epoch_from_civil(year, month, day).ok_or_else(invalid);
+}
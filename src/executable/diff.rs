@@ -0,0 +1,75 @@
+use archer_package_manager::error::APMErrorType;
+use archer_package_manager::packages::{diff, ChangeKind, DiffOptions, Package, PackageDiff};
+
+use crate::output::{CommandError, Output, Report};
+
+pub fn execute_diff_op(
+    old_path: String,
+    new_path: String,
+    include_checksum: bool,
+    stat: bool,
+    context_lines: usize,
+    out: &Output,
+) -> Result<Report, CommandError> {
+    let options = DiffOptions {
+        include_checksum,
+        text_diff: !stat,
+        context_lines,
+    };
+
+    let mut old = Package::open(&old_path)?;
+    let mut new = Package::open(&new_path)?;
+    let result = diff(&mut old, &mut new, &options)?;
+    let value = serde_json::to_value(&result)
+        .map_err(|e| APMErrorType::JSONSerializeError.from_error(e))?;
+
+    // In JSON mode the result is printed once the command finishes
+    if !out.is_json() {
+        print_diff(&result);
+    }
+
+    return Ok(Report::new(value));
+}
+
+fn print_diff(result: &PackageDiff) {
+    if result.is_empty() {
+        println!("The packages have the same contents");
+        return;
+    }
+
+    for change in &result.changes {
+        match change.kind {
+            ChangeKind::Added => println!(
+                "Added:    {} ({} bytes)",
+                change.name,
+                change.new_size.unwrap_or(0)
+            ),
+            ChangeKind::Removed => println!(
+                "Removed:  {} ({} bytes)",
+                change.name,
+                change.old_size.unwrap_or(0)
+            ),
+            ChangeKind::Modified => println!(
+                "Modified: {} ({} -> {} bytes, {:+})",
+                change.name,
+                change.old_size.unwrap_or(0),
+                change.new_size.unwrap_or(0),
+                change.size_delta
+            ),
+        }
+
+        if let Some(text_diff) = &change.text_diff {
+            println!();
+            print!("{}", text_diff);
+        }
+    }
+
+    println!();
+    println!(
+        "{} entries changed, package size {} -> {} bytes ({:+})",
+        result.changes.len(),
+        result.old_size,
+        result.new_size,
+        result.size_delta
+    );
+}
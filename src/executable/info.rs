@@ -0,0 +1,136 @@
+use archer_package_manager::error::APMErrorType;
+use archer_package_manager::packages::{EntryInfo, Package, PackageInfo};
+use std::collections::BTreeMap;
+
+use crate::manager::open_store;
+use crate::output::{CommandError, Output, Report};
+
+#[derive(Default)]
+struct TreeNode {
+    children: BTreeMap<String, TreeNode>,
+    size: Option<u64>,
+}
+
+pub fn execute_info_op(
+    store_path: Option<String>,
+    name: Option<String>,
+    version: Option<String>,
+    path: Option<String>,
+    out: &Output,
+) -> Result<Report, CommandError> {
+    let mut package;
+
+    if let Some(name) = name {
+        let store = open_store(store_path)?;
+        let revision = store.find(&name, version.as_deref())?;
+
+        package = store.open_package(revision)?;
+    } else if let Some(path) = path {
+        package = Package::open(&path)?;
+    } else {
+        return Err(CommandError::Usage("No path specified".to_string()));
+    }
+
+    let info = package.info()?;
+    let result =
+        serde_json::to_value(&info).map_err(|e| APMErrorType::JSONSerializeError.from_error(e))?;
+
+    // In JSON mode the result is printed once the command finishes
+    if !out.is_json() {
+        print_info(&info);
+    }
+
+    return Ok(Report::new(result));
+}
+
+fn print_info(info: &PackageInfo) {
+    if let Some(p) = &info.source_path {
+        println!("Package: {}", p);
+    }
+
+    println!("File size: {} bytes", info.file_size);
+    println!("Entries: {}", info.entry_count);
+    println!("Compressed size: {} bytes", info.compressed_size);
+    println!("Uncompressed size: {} bytes", info.uncompressed_size);
+
+    let methods: Vec<String> = info
+        .compression_methods
+        .iter()
+        .map(|(method, count)| format!("{} ({})", method, count))
+        .collect();
+
+    println!("Compression methods: {}", methods.join(", "));
+
+    match &info.stored_checksum {
+        Some(checksum) => {
+            println!("Checksum: {}", checksum);
+            println!(
+                "Checksum valid: {}",
+                if info.checksum_valid { "yes" } else { "no" }
+            );
+
+            if !info.checksum_valid {
+                println!("Computed checksum: {}", info.computed_checksum);
+            }
+        }
+        None => {
+            println!("Checksum: Not found");
+            println!("Computed checksum: {}", info.computed_checksum);
+        }
+    }
+
+    println!();
+    println!("Contents:");
+    print_tree(&build_tree(&info.entries), "");
+}
+
+fn build_tree(entries: &[EntryInfo]) -> TreeNode {
+    let mut root = TreeNode::default();
+
+    for entry in entries {
+        let mut node = &mut root;
+
+        // Keep the leading slash of absolute entries such as /hidden visible in the tree
+        let (leading, name) = match entry.name.strip_prefix('/') {
+            Some(stripped) => ("/", stripped),
+            None => ("", entry.name.as_str()),
+        };
+
+        for (i, component) in name.split('/').filter(|c| !c.is_empty()).enumerate() {
+            let key = if i == 0 {
+                format!("{}{}", leading, component)
+            } else {
+                component.to_string()
+            };
+
+            node = node.children.entry(key).or_default();
+        }
+
+        if !entry.is_dir {
+            node.size = Some(entry.size);
+        }
+    }
+
+    return root;
+}
+
+fn print_tree(node: &TreeNode, prefix: &str) {
+    let count = node.children.len();
+
+    for (i, (name, child)) in node.children.iter().enumerate() {
+        let last = i + 1 == count;
+        let branch = if last { "└── " } else { "├── " };
+
+        match child.size {
+            Some(size) if child.children.is_empty() => {
+                println!("{}{}{} ({} bytes)", prefix, branch, name, size)
+            }
+            _ => println!("{}{}{}/", prefix, branch, name),
+        }
+
+        print_tree(
+            child,
+            &format!("{}{}", prefix, if last { "    " } else { "│   " }),
+        );
+    }
+}
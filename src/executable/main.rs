@@ -1,18 +1,78 @@
+#![allow(clippy::needless_return)]
+
+mod batch;
 mod cli;
+mod diff;
+mod info;
+mod manager;
 mod modifier;
+mod output;
 mod util;
 
 use clap::StructOpt;
 use cli::CLIArgs;
+use std::process::exit;
 
 use cli::Command;
+use diff::execute_diff_op;
+use info::execute_info_op;
+use manager::execute_manager_op;
 use modifier::execute_modifier_op;
+use output::{Output, OutputFormat, SUCCESS_EXIT_CODE, USAGE_EXIT_CODE};
+use util::OverwriteMode;
 
 fn main() {
-    let args = CLIArgs::parse();
+    let args = match CLIArgs::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            // Help and version output is reported through an error without being one
+            let code = if e.use_stderr() {
+                USAGE_EXIT_CODE
+            } else {
+                SUCCESS_EXIT_CODE
+            };
+
+            let _ = e.print();
+            exit(code);
+        }
+    };
+    // The --json flag of info and diff is a shorthand for --format json.
+    let format = match &args.command {
+        Command::Info { json: true, .. } | Command::Diff { json: true, .. } => OutputFormat::Json,
+        _ => args.format,
+    };
+    let out = Output::new(format);
+    let overwrite = OverwriteMode::from_flags(args.force, args.no_clobber);
+
+    let outcome = match args.command {
+        Command::Manager { operation } => {
+            execute_manager_op(args.store, operation, overwrite, args.backup, &out)
+        }
+        Command::Modifier { operation } => {
+            execute_modifier_op(args.store, operation, overwrite, args.backup, &out)
+        }
+        Command::Info {
+            name,
+            version,
+            path,
+            ..
+        } => execute_info_op(args.store, name, version, path, &out),
+        Command::Diff {
+            old_path,
+            new_path,
+            include_checksum,
+            stat,
+            context_lines,
+            ..
+        } => execute_diff_op(
+            old_path,
+            new_path,
+            include_checksum,
+            stat,
+            context_lines,
+            &out,
+        ),
+    };
 
-    match args.command {
-        Command::Manager => eprintln!("Error: Manager is not enabled."),
-        Command::Modifier { operation } => execute_modifier_op(operation),
-    }
+    exit(out.finish(outcome));
 }
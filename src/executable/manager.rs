@@ -0,0 +1,491 @@
+use archer_package_manager::datetime::format_timestamp;
+use archer_package_manager::error::{APMError, APMErrorType};
+use archer_package_manager::packages::Package;
+use archer_package_manager::store::{
+    parse_package_ref, GcOptions, Metadata, PackageDetails, PackageStore, Problem, Revision,
+    SearchQuery, Storage, VersionSpec,
+};
+use serde_json::json;
+use std::time::Duration;
+
+use crate::cli::ManagerOperation;
+use crate::output::{CommandError, Output, Report, STORE_EXIT_CODE, SUCCESS_EXIT_CODE};
+use crate::util::{confirm_overwrite, OverwriteMode};
+
+pub fn open_store(store_path: Option<String>) -> Result<PackageStore, APMError> {
+    return match store_path {
+        Some(p) => PackageStore::open(p),
+        None => PackageStore::open(PackageStore::default_path()?),
+    };
+}
+
+pub fn execute_manager_op(
+    store_path: Option<String>,
+    op: ManagerOperation,
+    overwrite: OverwriteMode,
+    backup: bool,
+    out: &Output,
+) -> Result<Report, CommandError> {
+    let mut store = open_store(store_path)?;
+
+    match op {
+        ManagerOperation::Add {
+            name,
+            path,
+            version,
+            metadata,
+        } => {
+            let mut details = PackageDetails::default();
+
+            metadata.apply(&mut details);
+
+            let revision = store.add_package_with_details(
+                &name,
+                version.as_deref(),
+                &mut Package::open(&path)?,
+                details,
+            )?;
+
+            out.line(format!("Added {} revision {}", name, revision.revision));
+            print_revision(out, revision);
+
+            return Ok(Report::new(json!({
+                "name": name,
+                "revision": revision,
+            })));
+        }
+        ManagerOperation::List { verbose } => {
+            let mut empty = true;
+
+            for (name, record) in store.packages() {
+                empty = false;
+
+                if verbose {
+                    out.line(name);
+
+                    for revision in &record.revisions {
+                        out.line(format!(
+                            "  r{} {} {}",
+                            revision.revision,
+                            revision.version_string(),
+                            format_timestamp(revision.added)
+                        ));
+                    }
+                } else if let Some(latest) = record.latest() {
+                    out.line(format!(
+                        "{} {} ({} revisions)",
+                        name,
+                        latest.version_string(),
+                        record.revisions.len()
+                    ));
+                }
+            }
+
+            if empty {
+                out.line(format!("The store at {} is empty", store.root().display()));
+            }
+
+            let packages: serde_json::Map<String, serde_json::Value> = store
+                .packages()
+                .map(|(name, record)| (name.to_string(), json!(record)))
+                .collect();
+
+            return Ok(Report::new(json!({
+                "store": store.root().display().to_string(),
+                "packages": packages,
+            })));
+        }
+        ManagerOperation::Remove { name, version } => {
+            let removed = store.remove(&name, version.as_deref())?;
+
+            for revision in &removed {
+                out.line(format!("Removed {} revision {}", name, revision.revision));
+            }
+
+            return Ok(Report::new(json!({
+                "name": name,
+                "removed": removed,
+            })));
+        }
+        ManagerOperation::Show { name } => {
+            let record = store.get(&name).ok_or_else(|| {
+                APMErrorType::PackageNotFoundError
+                    .into_apm_error(format!("There is no package named {} in the store", name))
+            })?;
+            let mut revisions = Vec::new();
+
+            out.line(format!("Name: {}", name));
+
+            for revision in &record.revisions {
+                let path = store.revision_path(revision);
+
+                out.line("");
+                out.line(format!("Revision: {}", revision.revision));
+                print_revision(out, revision);
+                out.line(format!(
+                    "Storage: {}",
+                    match revision.storage {
+                        Storage::Zip => "zip",
+                        Storage::Blobs => "blobs",
+                    }
+                ));
+                out.line(format!("Path: {}", path.display()));
+
+                revisions.push(json!({
+                    "revision": revision,
+                    "path": path.display().to_string(),
+                }));
+            }
+
+            return Ok(Report::new(json!({
+                "name": name,
+                "revisions": revisions,
+            })));
+        }
+        ManagerOperation::Versions { name } => {
+            let record = store.get(&name).ok_or_else(|| {
+                APMErrorType::PackageNotFoundError
+                    .into_apm_error(format!("There is no package named {} in the store", name))
+            })?;
+            let latest = record.latest().map(|r| r.revision);
+            let versions = record.versions();
+
+            for revision in &versions {
+                out.line(format!(
+                    "{} {:<12} r{:<4} {}",
+                    if Some(revision.revision) == latest {
+                        "*"
+                    } else {
+                        " "
+                    },
+                    revision.version_string(),
+                    revision.revision,
+                    format_timestamp(revision.added)
+                ));
+            }
+
+            return Ok(Report::new(json!({
+                "name": name,
+                "latest": latest,
+                "versions": versions,
+            })));
+        }
+        ManagerOperation::Get { package, output } => {
+            let (name, version) = parse_package_ref(&package);
+            let revision = store.find(name, version)?;
+            let dest = output.unwrap_or_else(|| match &revision.version {
+                Some(v) => format!("{}-{}.zip", name, v),
+                None => format!("{}-r{}.zip", name, revision.revision),
+            });
+
+            confirm_overwrite(overwrite, &dest)?;
+            let summary = store.export(revision, &dest, backup)?;
+
+            out.line(format!(
+                "Saved {} {} (revision {}) to {}",
+                name,
+                revision.version_string(),
+                revision.revision,
+                dest
+            ));
+
+            if !summary.identical {
+                out.line(
+                    "The package was rebuilt with the same contents but is not byte for byte the package which was stored",
+                );
+            }
+
+            if let Some(p) = &summary.backup {
+                out.line(format!("Backup: {}", p.display()));
+            }
+
+            return Ok(Report::new(json!({
+                "name": name,
+                "revision": revision,
+                "output": dest,
+                "identical": summary.identical,
+                "backup": summary.backup,
+            })));
+        }
+        ManagerOperation::SetLatest { package } => {
+            let (name, version) = parse_package_ref(&package);
+            let version = version.ok_or_else(|| {
+                CommandError::Usage(format!("Specify the version to use, e.g. {}@1.2", name))
+            })?;
+            let revision = store.set_latest(name, version)?;
+
+            out.line(format!(
+                "The latest version of {} is now {} (revision {})",
+                name,
+                revision.version_string(),
+                revision.revision
+            ));
+
+            return Ok(Report::new(json!({
+                "name": name,
+                "latest": revision,
+            })));
+        }
+        ManagerOperation::Rollback { name } => {
+            let revision = store.rollback(&name)?;
+
+            out.line(format!(
+                "Rolled {} back to {} (revision {})",
+                name,
+                revision.version_string(),
+                revision.revision
+            ));
+
+            return Ok(Report::new(json!({
+                "name": name,
+                "latest": revision,
+            })));
+        }
+        ManagerOperation::Meta {
+            package,
+            metadata,
+            remove_tags,
+        } => {
+            let (name, version) = parse_package_ref(&package);
+            let revision = store.find(name, version)?.clone();
+            let mut current = store.metadata(name, &revision)?;
+
+            if !metadata.is_empty() || !remove_tags.is_empty() {
+                let mut details = current.details.clone();
+
+                metadata.apply(&mut details);
+                details.tags.retain(|t| !remove_tags.contains(t));
+
+                current = store.set_details(name, &revision, details)?;
+            }
+
+            print_metadata(out, &current);
+
+            return Ok(Report::new(json!(current)));
+        }
+        ManagerOperation::Search {
+            name,
+            tags,
+            version,
+            since,
+            until,
+            archer_version,
+            checksum,
+            all,
+            sort,
+            reverse,
+        } => {
+            let query = SearchQuery {
+                name,
+                tags,
+                version: version.as_deref().map(VersionSpec::parse).transpose()?,
+                created_since: since,
+                // Include the whole of the last day
+                created_until: until.map(|t| t + 86399),
+                archer_version,
+                checksum,
+                all_revisions: all,
+                order: sort.into(),
+                reverse,
+            };
+            let results = store.search(&query);
+
+            print_search_results(out, &results);
+
+            return Ok(Report::new(json!({
+                "results": results
+                    .iter()
+                    .map(|(name, revision)| json!({ "name": name, "revision": revision }))
+                    .collect::<Vec<_>>(),
+            })));
+        }
+        ManagerOperation::Gc {
+            dry_run,
+            min_age,
+            verbose,
+        } => {
+            let summary = store.gc(&GcOptions {
+                dry_run,
+                min_age: Duration::from_secs(min_age),
+            })?;
+
+            if verbose {
+                for blob in &summary.removed_blobs {
+                    out.line(format!("Blob: {}", blob));
+                }
+
+                for p in &summary.removed_temp_files {
+                    out.line(format!("Temporary file: {}", p.display()));
+                }
+            }
+
+            out.line(format!(
+                "{} {} blobs and {} temporary files, freeing {} bytes",
+                if dry_run { "Would remove" } else { "Removed" },
+                summary.removed_blobs.len(),
+                summary.removed_temp_files.len(),
+                summary.freed_bytes
+            ));
+
+            return Ok(Report::new(json!({
+                "dry_run": dry_run,
+                "removed_blobs": summary.removed_blobs,
+                "removed_temp_files": summary.removed_temp_files,
+                "freed_bytes": summary.freed_bytes,
+            })));
+        }
+        ManagerOperation::Fsck { quarantine } => {
+            let summary = store.fsck(quarantine)?;
+
+            for p in &summary.problems {
+                out.line(format!(
+                    "{} revision {}: {}",
+                    p.name,
+                    p.revision,
+                    describe_problem(&p.problem)
+                ));
+            }
+
+            for p in &summary.quarantined {
+                out.line(format!("Quarantined: {}", p.display()));
+            }
+
+            out.line(format!(
+                "Checked {} revisions and {} blobs, found {} problems",
+                summary.revisions,
+                summary.blobs,
+                summary.problems.len()
+            ));
+
+            let exit_code = match summary.problems.is_empty() {
+                true => SUCCESS_EXIT_CODE,
+                false => STORE_EXIT_CODE,
+            };
+
+            return Ok(Report::new(json!(summary)).with_exit_code(exit_code));
+        }
+    }
+}
+
+fn describe_problem(problem: &Problem) -> String {
+    return match problem {
+        Problem::MissingFile { path } => format!("{} is missing", path.display()),
+        Problem::Unreadable { error } => format!("cannot be read: {}", error),
+        Problem::SizeMismatch { recorded, actual } => format!(
+            "the package is {} bytes but {} were recorded",
+            actual, recorded
+        ),
+        Problem::ChecksumMismatch { stored, computed } => format!(
+            "the stored checksum {} does not match the contents, {}",
+            stored, computed
+        ),
+        Problem::RecordedChecksumMismatch { recorded, stored } => format!(
+            "the stored checksum {} is not the one recorded when it was added, {}",
+            stored.as_deref().unwrap_or("(none)"),
+            recorded.as_deref().unwrap_or("(none)")
+        ),
+        Problem::MissingBlob { blob, entry } => {
+            format!("the blob {} for {} is missing", blob, entry)
+        }
+        Problem::CorruptBlob { blob, entry } => {
+            format!("the blob {} for {} is corrupt", blob, entry)
+        }
+    };
+}
+
+fn print_search_results(out: &Output, results: &[(&str, &Revision)]) {
+    if results.is_empty() {
+        out.line("No packages found");
+        return;
+    }
+
+    let rows: Vec<[String; 6]> = results
+        .iter()
+        .map(|(name, r)| {
+            return [
+                name.to_string(),
+                r.version_string(),
+                r.revision.to_string(),
+                format_timestamp(r.added),
+                r.details.archer_version.clone().unwrap_or("-".to_string()),
+                r.details.tags.join(","),
+            ];
+        })
+        .collect();
+    let header = ["NAME", "VERSION", "REV", "ADDED", "ARCHER", "TAGS"];
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|r| r[i].len())
+                .chain([header[i].len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let format_row = |cells: [&str; 6]| {
+        return cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<w$}", c, w = w))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string();
+    };
+
+    out.line(format_row(header));
+
+    for row in &rows {
+        out.line(format_row(row.each_ref().map(|c| c.as_str())));
+    }
+}
+
+fn print_metadata(out: &Output, metadata: &Metadata) {
+    let optional = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+
+    out.line(format!("Name: {}", metadata.name));
+    out.line(format!(
+        "Version: {}",
+        metadata
+            .version
+            .as_ref()
+            .map_or("-".to_string(), |v| v.to_string())
+    ));
+    out.line(format!("Revision: {}", metadata.revision));
+    out.line(format!(
+        "Description: {}",
+        optional(&metadata.details.description)
+    ));
+    out.line(format!("Author: {}", optional(&metadata.details.author)));
+    out.line(format!(
+        "Archer version: {}",
+        optional(&metadata.details.archer_version)
+    ));
+    out.line(format!("Created: {}", format_timestamp(metadata.created)));
+    out.line(format!(
+        "Source directory: {}",
+        optional(&metadata.details.source_directory)
+    ));
+    out.line(format!(
+        "Checksum: {}",
+        metadata.checksum.as_deref().unwrap_or("Not found")
+    ));
+    out.line(format!("Size: {} bytes", metadata.size));
+    out.line(format!(
+        "Tags: {}",
+        match metadata.details.tags.is_empty() {
+            true => "-".to_string(),
+            false => metadata.details.tags.join(", "),
+        }
+    ));
+}
+
+fn print_revision(out: &Output, revision: &Revision) {
+    out.line(format!("Version: {}", revision.version_string()));
+    out.line(format!(
+        "Checksum: {}",
+        revision.checksum.as_deref().unwrap_or("Not found")
+    ));
+    out.line(format!("Size: {} bytes", revision.size));
+    out.line(format!("Added: {}", format_timestamp(revision.added)));
+}
@@ -1,114 +1,338 @@
 use archer_package_manager::error::{APMError, APMErrorType};
-use archer_package_manager::{packages, zip_manipulation};
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::Path;
-use std::process::exit;
-
-use crate::cli::ModiferOperation;
-use crate::util::y_n_question;
-
-pub fn execute_modifier_op(op: ModiferOperation) {
-    match execute_op(op) {
-        Ok(_) => (),
-        Err(e) => {
-            eprintln!("Error - {}", e.error_type());
-            eprintln!("Description: {}", e.description());
-        }
-    }
-}
-
-fn execute_op(op: ModiferOperation) -> Result<(), APMError> {
+use archer_package_manager::packages::{ChecksumStatus, ExtractOptions, OverwritePolicy, Package};
+use archer_package_manager::store::{parse_version, PackageDetails, PackageStore};
+use archer_package_manager::zip_manipulation::{
+    source_date_epoch, CompressOptions, CompressSummary, CompressionSettings,
+};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+
+use crate::batch::{expand_paths, is_batch, run_batch, Outcome};
+use crate::cli::{CompressionArgs, ModiferOperation};
+use crate::manager::open_store;
+use crate::output::{
+    CommandError, Output, Report, CHECKSUM_MISMATCH_EXIT_CODE, CHECKSUM_MISSING_EXIT_CODE,
+};
+use crate::util::{confirm_batch, confirm_overwrite, OverwriteMode};
+
+pub fn execute_modifier_op(
+    store_path: Option<String>,
+    op: ModiferOperation,
+    overwrite: OverwriteMode,
+    backup: bool,
+    out: &Output,
+) -> Result<Report, CommandError> {
     match op {
         ModiferOperation::RemoveChecksum {
             name,
+            version,
             path,
             output_path,
             verbose,
+            jobs,
+            compression,
         } => {
-            if let Some(_name) = name {
-                eprintln!("Error: Manager is not enabled.");
-                exit(1);
-            }
+            let compression = validated_settings(&compression)?;
 
-            if let Some(path) = path {
-                let p = Path::new(&path);
+            if let Some(name) = name {
+                let mut store = open_store(store_path)?;
+                let (mut package, stored_version, details) = open_named(&store, &name, version)?;
 
-                if !p.exists() {
-                    eprintln!("Error: There is no file at {}", path);
-                    exit(1);
+                if verbose {
+                    print_file_names(out, &mut package)?;
                 }
 
+                if !package.contents()?.has_checksum_entry() {
+                    return Err(checksum_not_found(&package).into());
+                }
+
+                if let Some(dest) = &output_path {
+                    confirm_overwrite(overwrite, dest)?;
+                }
+
+                package.remove_checksum()?;
+                recompress(&mut package, compression.as_ref())?;
+
+                let new_revision = store.add_package_with_details(
+                    &name,
+                    stored_version.as_deref(),
+                    &mut package,
+                    details,
+                )?;
+
+                out.line("Checksum file removed");
+                out.line(format!(
+                    "Stored: {} revision {}",
+                    name, new_revision.revision
+                ));
+
+                let backup_path = save_output(out, &package, output_path.as_deref(), backup)?;
+
+                return Ok(Report::new(json!({
+                    "removed": true,
+                    "name": name,
+                    "revision": new_revision,
+                    "output": output_path,
+                    "backup": backup_path,
+                })));
+            } else if is_batch(&path) {
+                if output_path.is_some() {
+                    return Err(CommandError::Usage(
+                        "-o can only be used with a single package".to_string(),
+                    ));
+                }
+
+                let packages = expand_paths(&path)?;
+
+                confirm_batch(overwrite, packages.len())?;
+
+                return run_batch(out, &packages, jobs, |p| {
+                    remove_checksum_in_place(p, backup, compression.as_ref())
+                });
+            } else if let Some(path) = path.into_iter().next() {
                 let dest = output_path.unwrap_or(path.clone());
+                let mut package = Package::open(&path)?;
 
                 if verbose {
-                    println!("Files:");
-                    for f in packages::dump_file_names_zip(&path)? {
-                        println!("{}", f);
-                    }
-                    println!();
+                    print_file_names(out, &mut package)?;
                 }
 
-                let (zip, found) = packages::remove_checksum_zip(&path)?;
-
-                if !found {
-                    eprintln!("Checksum not found");
-                    return Ok(());
+                if !package.contents()?.has_checksum_entry() {
+                    return Err(checksum_not_found(&package).into());
                 }
 
-                write_bytes(&zip, &dest)?;
+                confirm_overwrite(overwrite, &dest)?;
+                package.remove_checksum()?;
+                recompress(&mut package, compression.as_ref())?;
 
-                println!("Checksum file removed");
-                println!("Output: {}", dest);
-            } else {
-                eprintln!("Error: No path specified");
+                out.line("Checksum file removed");
+
+                let backup_path = save_output(out, &package, Some(&dest), backup)?;
+
+                return Ok(Report::new(json!({
+                    "removed": true,
+                    "output": dest,
+                    "backup": backup_path,
+                })));
             }
+
+            return Err(CommandError::Usage("No path specified".to_string()));
         }
         ModiferOperation::AddChecksum {
             name,
+            version,
             path,
             output_path,
-            remove_checksum,
+            replace,
+            jobs,
+            compression,
         } => {
-            if let Some(_name) = name {
-                eprintln!("Error: Manager is not enabled.");
-                exit(1);
-            }
+            let compression = validated_settings(&compression)?;
 
-            if let Some(path) = path {
-                let p = Path::new(&path);
+            if let Some(name) = name {
+                let mut store = open_store(store_path)?;
+                let (mut package, stored_version, details) = open_named(&store, &name, version)?;
 
-                if !p.exists() {
-                    eprintln!("Error: There is no file at {}", path);
-                    exit(1);
+                if let Some(dest) = &output_path {
+                    confirm_overwrite(overwrite, dest)?;
                 }
 
-                let dest = output_path.unwrap_or(path.clone());
+                let (checksum, replaced) =
+                    add_or_replace_checksum(&mut package, replace, compression.as_ref())?;
 
-                if Path::new(&dest).exists() {
-                    if !y_n_question(&format!(
-                        "There already exists a file at {}\nOverwrite?",
-                        dest
-                    )) {
-                        eprintln!("Aborting");
-                        exit(0);
-                    }
+                if replaced {
+                    out.line("Replaced the existing checksum");
                 }
 
-                if remove_checksum {
-                    println!("Removing checksum if found");
+                let new_revision = store.add_package_with_details(
+                    &name,
+                    stored_version.as_deref(),
+                    &mut package,
+                    details,
+                )?;
+
+                out.line(format!("Checksum Added: {}", checksum));
+                out.line(format!(
+                    "Stored: {} revision {}",
+                    name, new_revision.revision
+                ));
+
+                let backup_path = save_output(out, &package, output_path.as_deref(), backup)?;
+
+                return Ok(Report::new(json!({
+                    "checksum": checksum,
+                    "name": name,
+                    "revision": new_revision,
+                    "output": output_path,
+                    "backup": backup_path,
+                })));
+            } else if is_batch(&path) {
+                if output_path.is_some() {
+                    return Err(CommandError::Usage(
+                        "-o can only be used with a single package".to_string(),
+                    ));
+                }
+
+                let packages = expand_paths(&path)?;
+
+                confirm_batch(overwrite, packages.len())?;
+
+                return run_batch(out, &packages, jobs, |p| {
+                    add_checksum_in_place(p, replace, backup, compression.as_ref())
+                });
+            } else if let Some(path) = path.into_iter().next() {
+                let dest = output_path.unwrap_or(path.clone());
+                let mut package = Package::open(&path)?;
+
+                confirm_overwrite(overwrite, &dest)?;
+
+                let (checksum, replaced) =
+                    add_or_replace_checksum(&mut package, replace, compression.as_ref())?;
+
+                if replaced {
+                    out.line("Replaced the existing checksum");
                 }
 
-                let (zip, checksum) = packages::insert_checksum_zip(&path, remove_checksum)?;
+                out.line(format!("Checksum Added: {}", checksum));
+
+                let backup_path = save_output(out, &package, Some(&dest), backup)?;
+
+                return Ok(Report::new(json!({
+                    "checksum": checksum,
+                    "output": dest,
+                    "backup": backup_path,
+                })));
+            }
+
+            return Err(CommandError::Usage("No path specified".to_string()));
+        }
+        ModiferOperation::Verify {
+            name,
+            version,
+            path,
+            jobs,
+        } => {
+            let mut package;
+
+            if let Some(name) = name {
+                let store = open_store(store_path)?;
+
+                package = open_named(&store, &name, version)?.0;
+            } else if is_batch(&path) {
+                return run_batch(out, &expand_paths(&path)?, jobs, verify_package);
+            } else if let Some(path) = path.into_iter().next() {
+                package = Package::open(&path)?;
+            } else {
+                return Err(CommandError::Usage("No path specified".to_string()));
+            }
 
-                write_bytes(&zip, &dest)?;
+            match package.verify()? {
+                ChecksumStatus::Valid(checksum) => {
+                    out.line(format!("Checksum valid: {}", checksum));
 
-                println!("Checksum Added: {}", checksum);
-                println!("Output: {}", dest);
+                    return Ok(Report::new(json!({
+                        "status": "valid",
+                        "stored": checksum,
+                        "computed": checksum,
+                    })));
+                }
+                ChecksumStatus::Mismatch { stored, computed } => {
+                    out.line("Checksum mismatch");
+                    out.line(format!("Stored: {}", stored));
+                    out.line(format!("Computed: {}", computed));
+
+                    return Ok(Report::new(json!({
+                        "status": "mismatch",
+                        "stored": stored,
+                        "computed": computed,
+                    }))
+                    .with_exit_code(CHECKSUM_MISMATCH_EXIT_CODE));
+                }
+                ChecksumStatus::Missing { computed } => {
+                    out.line("Checksum missing");
+                    out.line(format!("Computed: {}", computed));
+
+                    return Ok(Report::new(json!({
+                        "status": "missing",
+                        "stored": null,
+                        "computed": computed,
+                    }))
+                    .with_exit_code(CHECKSUM_MISSING_EXIT_CODE));
+                }
+            }
+        }
+        ModiferOperation::Extract {
+            name,
+            version,
+            path,
+            output_directory,
+            checksum_file,
+            overwrite: overwrite_existing,
+            skip_existing,
+            verbose,
+        } => {
+            let mut package;
+            let default_dest;
+
+            if let Some(name) = name {
+                let store = open_store(store_path)?;
+
+                package = open_named(&store, &name, version)?.0;
+                default_dest = name;
+            } else if let Some(path) = path {
+                package = Package::open(&path)?;
+                default_dest = Path::new(&path)
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or("output")
+                    .to_string();
             } else {
-                eprintln!("Error: No path specifed.");
-                exit(1);
+                return Err(CommandError::Usage("No path specified".to_string()));
+            }
+
+            // Checked before anything is extracted so a script asking for the checksum file can
+            // tell it is missing
+            if checksum_file.is_some() && !package.contents()?.has_checksum_entry() {
+                return Err(checksum_not_found(&package).into());
             }
+
+            let dest = output_directory.unwrap_or(default_dest);
+            let options = ExtractOptions {
+                overwrite: if overwrite_existing || overwrite == OverwriteMode::Always {
+                    OverwritePolicy::Overwrite
+                } else if skip_existing || overwrite == OverwriteMode::Never {
+                    OverwritePolicy::Skip
+                } else {
+                    OverwritePolicy::Error
+                },
+                checksum_path: checksum_file.map(PathBuf::from),
+            };
+
+            let summary = package.extract(&dest, &options)?;
+
+            if verbose {
+                for f in &summary.extracted {
+                    out.line(format!("Extracted: {}", f));
+                }
+            }
+
+            for f in &summary.skipped {
+                out.line(format!("Skipped existing file: {}", f));
+            }
+
+            if let (Some(_), Some(checksum_path)) = (&summary.checksum, &options.checksum_path) {
+                out.line(format!("Checksum: {}", checksum_path.display()));
+            }
+
+            out.line(format!("Output: {}", dest));
+
+            return Ok(Report::new(json!({
+                "output": dest,
+                "extracted": summary.extracted,
+                "skipped": summary.skipped,
+                "checksum": summary.checksum,
+            })));
         }
         ModiferOperation::MakePackage {
             add_to_db,
@@ -116,36 +340,51 @@ fn execute_op(op: ModiferOperation) -> Result<(), APMError> {
             name,
             version,
             output_path,
+            reproducible,
+            timestamp,
+            exclude,
+            include,
+            symlinks,
+            compression,
+            metadata,
             verbose,
         } => {
-            if add_to_db {
-                eprintln!("Error: Manager is not enabled.");
-                exit(1);
+            let dir_path = Path::new(&input_directory);
+
+            if !add_to_db && !metadata.is_empty() {
+                return Err(CommandError::Usage(
+                    "Metadata can only be recorded when the package is added to the store with --add"
+                        .to_string(),
+                ));
             }
 
-            let dir_path = Path::new(&input_directory);
+            if !dir_path.is_dir() {
+                return Err(APMErrorType::FileOpenError
+                    .into_apm_error(format!("There is no directory at {}", input_directory))
+                    .with_path(dir_path)
+                    .into());
+            }
 
-            if !dir_path.exists() {
-                eprintln!("Error: Could not find a directory at {}", input_directory);
-                exit(1);
-            } else if !dir_path.is_dir() {
-                eprintln!("Error: There was no directory found at {}", input_directory);
-                exit(1);
+            if let Some(version) = &version {
+                parse_version(version)?;
             }
 
             let dest;
+            let explicit_output = output_path.clone();
 
             if let Some(output_path) = output_path {
                 dest = output_path;
-            } else if let Some(name) = name {
-                if let Some(version) = version {
+            } else if let Some(name) = &name {
+                if let Some(version) = &version {
                     dest = format!("{}-v{}.zip", name, version);
                 } else {
                     dest = format!("{}.zip", name);
                 }
             } else {
-                if let Some(Some(last_component)) =
-                    dir_path.components().last().map(|v| v.as_os_str().to_str())
+                if let Some(Some(last_component)) = dir_path
+                    .components()
+                    .next_back()
+                    .map(|v| v.as_os_str().to_str())
                 {
                     dest = format!("{}.zip", last_component);
                 } else {
@@ -153,45 +392,415 @@ fn execute_op(op: ModiferOperation) -> Result<(), APMError> {
                 }
             }
 
-            println!("Compressing...");
+            if !add_to_db {
+                confirm_overwrite(overwrite, &dest)?;
+            } else if let Some(dest) = &explicit_output {
+                confirm_overwrite(overwrite, dest)?;
+            }
 
-            create_package_file(&input_directory, &dest, verbose)?;
+            out.line("Compressing...");
+
+            let options = CompressOptions {
+                track_file_names: verbose,
+                reproducible,
+                timestamp: match timestamp {
+                    Some(timestamp) => Some(timestamp),
+                    None => source_date_epoch()?,
+                },
+                exclude,
+                include,
+                symlinks: symlinks.into(),
+                compression: compression.settings().unwrap_or_default(),
+            };
+            let (mut package, summary, checksum) = create_package(out, &input_directory, &options)?;
 
-            println!("Successfully created package");
-            println!("Output: {}", dest);
+            if add_to_db {
+                // clap guarantees a name is present when adding to the store
+                let name = name.unwrap_or_default();
+                let mut store = open_store(store_path)?;
+                let mut details = PackageDetails {
+                    source_directory: Some(source_directory(dir_path)),
+                    ..Default::default()
+                };
+
+                metadata.apply(&mut details);
+
+                let revision = store.add_package_with_details(
+                    &name,
+                    version.as_deref(),
+                    &mut package,
+                    details,
+                )?;
+
+                out.line("Successfully created package");
+                out.line(format!("Stored: {} revision {}", name, revision.revision));
+
+                let backup_path = save_output(out, &package, explicit_output.as_deref(), backup)?;
+
+                return Ok(Report::new(json!({
+                    "checksum": checksum,
+                    "files": summary.file_names,
+                    "skipped_symlinks": summary.skipped_symlinks,
+                    "name": name,
+                    "revision": revision,
+                    "output": explicit_output,
+                    "backup": backup_path,
+                })));
+            }
+
+            out.line("Successfully created package");
+
+            let backup_path = save_output(out, &package, Some(&dest), backup)?;
+
+            return Ok(Report::new(json!({
+                "checksum": checksum,
+                "files": summary.file_names,
+                "skipped_symlinks": summary.skipped_symlinks,
+                "output": dest,
+                "backup": backup_path,
+            })));
         }
     }
+}
+
+// Opens the newest revision of a stored package matching `version`, returning the version and
+// details it was stored with so modified copies can be stored alongside it.
+fn open_named(
+    store: &PackageStore,
+    name: &str,
+    version: Option<String>,
+) -> Result<(Package, Option<String>, PackageDetails), APMError> {
+    let revision = store.find(name, version.as_deref())?;
+    let package = store.open_package(revision)?;
+    let details = store.metadata(name, revision)?.details;
+
+    return Ok((
+        package,
+        revision.version.as_ref().map(|v| v.to_string()),
+        details,
+    ));
+}
+
+// The absolute path of the directory a package was made from, as given if it cannot be resolved.
+fn source_directory(dir: &Path) -> String {
+    return dir
+        .canonicalize()
+        .unwrap_or_else(|_| dir.to_path_buf())
+        .display()
+        .to_string();
+}
+
+pub fn create_package(
+    out: &Output,
+    dir: &str,
+    options: &CompressOptions,
+) -> Result<(Package, CompressSummary, String), APMError> {
+    let (mut package, summary) = Package::from_directory(dir, options)?;
+
+    if let Some(files) = &summary.file_names {
+        for f in files {
+            out.line(format!("Compressed: {}", f));
+        }
+    }
+
+    for link in &summary.skipped_symlinks {
+        eprintln!("Warning: skipped symlink {}", link);
+    }
+
+    let checksum = package.add_checksum_with_options(options.file_options()?)?;
+
+    out.line(format!("Checksum: {}", checksum));
+
+    return Ok((package, summary, checksum));
+}
+
+// The compression settings given on the command line, checked before any work is done.
+fn validated_settings(args: &CompressionArgs) -> Result<Option<CompressionSettings>, APMError> {
+    let settings = args.settings();
+
+    if let Some(settings) = &settings {
+        settings.validate()?;
+    }
+
+    return Ok(settings);
+}
+
+fn recompress(
+    package: &mut Package,
+    settings: Option<&CompressionSettings>,
+) -> Result<(), APMError> {
+    if let Some(settings) = settings {
+        package.recompress(settings)?;
+    }
 
     return Ok(());
 }
 
-pub fn write_bytes(b: &[u8], p: &str) -> Result<(), APMError> {
-    let mut f = OpenOptions::new()
-        .read(false)
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(p)
-        .map_err(|e| APMErrorType::FileOpenError.into_apm_error(e.to_string()))?;
+// Adds the checksum entry, an existing one is only replaced when `replace` is set. The package is
+// recompressed first if `compression` is given. Returns the checksum and whether one was replaced.
+fn add_or_replace_checksum(
+    package: &mut Package,
+    replace: bool,
+    compression: Option<&CompressionSettings>,
+) -> Result<(String, bool), APMError> {
+    let has_checksum = package.contents()?.has_checksum_entry();
+
+    if has_checksum && !replace {
+        let e = APMErrorType::ChecksumExistsError
+            .into_apm_error("The package already has a checksum, use -r to replace it".to_string());
+
+        return Err(match package.source_path() {
+            Some(p) => e.with_path(p),
+            None => e,
+        });
+    }
+
+    // Recompressing drops the old checksum, the new one is compressed like the checksum of a
+    // package made with the same settings
+    if let Some(settings) = compression {
+        package.recompress(settings)?;
+
+        let options = settings.apply_default(FileOptions::default());
+
+        return Ok((package.add_checksum_with_options(options)?, has_checksum));
+    }
+
+    if !has_checksum {
+        return Ok((package.add_checksum()?, false));
+    }
+
+    return Ok((package.replace_checksum()?, true));
+}
 
-    f.write_all(b)
-        .map_err(|e| APMErrorType::FileWriteError.into_apm_error(e.to_string()))?;
+// The error for a package which has no checksum.
+fn checksum_not_found(package: &Package) -> APMError {
+    let e = APMErrorType::ZIPArchiveHiddenNotFoundError
+        .into_apm_error("The package has no checksum".to_string());
+
+    return match package.source_path() {
+        Some(p) => e.with_path(p),
+        None => e,
+    };
+}
+
+fn remove_checksum_in_place(
+    path: &str,
+    backup: bool,
+    compression: Option<&CompressionSettings>,
+) -> Result<Outcome, CommandError> {
+    let mut package = Package::open(path)?;
+
+    if !package.remove_checksum()? {
+        return Err(checksum_not_found(&package).into());
+    }
+
+    recompress(&mut package, compression)?;
+
+    let backup_path = package.save_with_backup(path, backup)?;
+
+    return Ok(Outcome::new(
+        "removed",
+        String::new(),
+        json!({ "removed": true, "backup": backup_path }),
+    ));
+}
+
+fn add_checksum_in_place(
+    path: &str,
+    replace: bool,
+    backup: bool,
+    compression: Option<&CompressionSettings>,
+) -> Result<Outcome, CommandError> {
+    let mut package = Package::open(path)?;
+    let (checksum, replaced) = add_or_replace_checksum(&mut package, replace, compression)?;
+    let status = if replaced { "replaced" } else { "added" };
+    let backup_path = package.save_with_backup(path, backup)?;
+
+    return Ok(Outcome::new(
+        status,
+        checksum.clone(),
+        json!({ "checksum": checksum, "backup": backup_path }),
+    ));
+}
+
+fn verify_package(path: &str) -> Result<Outcome, CommandError> {
+    return Ok(match Package::open(path)?.verify()? {
+        ChecksumStatus::Valid(checksum) => Outcome::new(
+            "valid",
+            checksum.clone(),
+            json!({ "stored": checksum, "computed": checksum }),
+        ),
+        ChecksumStatus::Mismatch { stored, computed } => Outcome::new(
+            "mismatch",
+            format!("stored {}, computed {}", stored, computed),
+            json!({ "stored": stored, "computed": computed }),
+        )
+        .with_exit_code(CHECKSUM_MISMATCH_EXIT_CODE),
+        ChecksumStatus::Missing { computed } => Outcome::new(
+            "missing",
+            format!("computed {}", computed),
+            json!({ "stored": null, "computed": computed }),
+        )
+        .with_exit_code(CHECKSUM_MISSING_EXIT_CODE),
+    });
+}
+
+// Saves the package to `dest` if one was given, returning where the replaced file was backed up.
+fn save_output(
+    out: &Output,
+    package: &Package,
+    dest: Option<&str>,
+    backup: bool,
+) -> Result<Option<String>, APMError> {
+    let dest = match dest {
+        Some(dest) => dest,
+        None => return Ok(None),
+    };
+    let backup_path = package
+        .save_with_backup(dest, backup)?
+        .map(|p| p.display().to_string());
+
+    out.line(format!("Output: {}", dest));
+
+    if let Some(p) = &backup_path {
+        out.line(format!("Backup: {}", p));
+    }
+
+    return Ok(backup_path);
+}
+
+fn print_file_names(out: &Output, package: &mut Package) -> Result<(), APMError> {
+    out.line("Files:");
+
+    for f in package.contents()?.file_names() {
+        out.line(f);
+    }
+
+    out.line("");
 
     return Ok(());
 }
 
-pub fn create_package_file(dir: &str, out: &str, verbose: bool) -> Result<(), APMError> {
-    let (zip_contents, files) = zip_manipulation::compress_directory(dir, verbose)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{CLIArgs, Command};
+    use crate::output::OutputFormat;
+    use clap::Parser;
+    use zip::CompressionMethod;
+
+    // Runs `apm mod` with `args`, returning the exit code.
+    fn run_modifier(args: &[&str]) -> i32 {
+        let args = CLIArgs::try_parse_from(["apm", "mod"].iter().chain(args)).unwrap();
+        let out = Output::new(OutputFormat::Text);
+
+        return match args.command {
+            Command::Modifier { operation } => out.finish(execute_modifier_op(
+                None,
+                operation,
+                OverwriteMode::Always,
+                false,
+                &out,
+            )),
+            _ => unreachable!(),
+        };
+    }
 
-    if let Some(files) = files {
-        for f in files {
-            println!("Compressed: {}", f);
+    #[test]
+    fn test_remove_missing_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = std::fs::read("test_files/no_hidden.zip").unwrap();
+
+        for name in ["a.zip", "b.zip"] {
+            std::fs::write(dir.path().join(name), &original).unwrap();
         }
+
+        let path = dir.path().join("a.zip").display().to_string();
+        let output = dir.path().join("out.zip").display().to_string();
+
+        assert_eq!(
+            run_modifier(&["-r", "-p", &path, "-o", &output]),
+            CHECKSUM_MISSING_EXIT_CODE
+        );
+        assert!(!Path::new(&output).exists());
+
+        let root = dir.path().display().to_string();
+
+        assert_eq!(
+            run_modifier(&["-r", "-p", &root]),
+            CHECKSUM_MISSING_EXIT_CODE
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn test_extract_missing_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+        let checksum = dir.path().join("checksum.txt");
+        let extract = |package: &str| {
+            return run_modifier(&[
+                "extract",
+                "-p",
+                package,
+                "-o",
+                output.to_str().unwrap(),
+                "--checksum-file",
+                checksum.to_str().unwrap(),
+            ]);
+        };
+
+        // Nothing is extracted when the checksum file cannot be written.
+        assert_eq!(
+            extract("test_files/no_hidden.zip"),
+            CHECKSUM_MISSING_EXIT_CODE
+        );
+        assert!(!output.exists() && !checksum.exists());
+
+        assert_eq!(extract("test_files/hidden.zip"), 0);
+        assert!(checksum.is_file());
     }
 
-    let (zip_contents, checksum) = packages::add_checksum_zip(zip_contents)?;
+    #[test]
+    fn test_add_checksum_compression() {
+        let stored = CompressionSettings {
+            method: Some(CompressionMethod::Stored),
+            ..Default::default()
+        };
+        let mut package = Package::open("test_files/info.zip").unwrap();
+
+        add_or_replace_checksum(&mut package, false, Some(&stored)).unwrap();
+
+        for entry in package.entries().unwrap() {
+            assert_eq!(
+                entry.compression,
+                CompressionMethod::Stored,
+                "{}",
+                entry.name
+            );
+        }
+
+        assert!(package.contents().unwrap().has_checksum_entry());
+        assert!(matches!(
+            package.verify().unwrap(),
+            ChecksumStatus::Valid(_)
+        ));
 
-    println!("Checksum: {}", checksum);
+        // Replacing keeps to the new settings as well
+        let deflated = CompressionSettings {
+            level: Some(1),
+            ..Default::default()
+        };
 
-    return write_bytes(&zip_contents, out);
+        add_or_replace_checksum(&mut package, true, Some(&deflated)).unwrap();
+
+        for entry in package.entries().unwrap() {
+            let expected = match entry.is_dir {
+                true => CompressionMethod::Stored,
+                false => CompressionMethod::Deflated,
+            };
+
+            assert_eq!(entry.compression, expected, "{}", entry.name);
+        }
+    }
 }
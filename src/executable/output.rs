@@ -0,0 +1,259 @@
+use archer_package_manager::error::{APMError, APMErrorType};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use std::fmt::Display;
+
+use crate::util::print_error;
+
+pub const SUCCESS_EXIT_CODE: i32 = 0;
+pub const GENERAL_EXIT_CODE: i32 = 1;
+pub const CHECKSUM_MISMATCH_EXIT_CODE: i32 = 2;
+pub const CHECKSUM_MISSING_EXIT_CODE: i32 = 3;
+pub const USAGE_EXIT_CODE: i32 = 4;
+pub const IO_EXIT_CODE: i32 = 5;
+pub const ZIP_EXIT_CODE: i32 = 6;
+pub const STORE_EXIT_CODE: i32 = 7;
+pub const ABORTED_EXIT_CODE: i32 = 8;
+
+#[derive(ValueEnum, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    Package(APMError),
+    Usage(String),
+    Aborted,
+}
+
+/// The result of a command, printed as JSON when `--format json` is used.
+pub struct Report {
+    exit_code: i32,
+    result: Value,
+}
+
+pub struct Output {
+    format: OutputFormat,
+}
+
+impl From<APMError> for CommandError {
+    fn from(e: APMError) -> Self {
+        return CommandError::Package(e);
+    }
+}
+
+impl CommandError {
+    pub fn exit_code(&self) -> i32 {
+        return match self {
+            CommandError::Package(e) => error_exit_code(e.error_type()),
+            CommandError::Usage(_) => USAGE_EXIT_CODE,
+            CommandError::Aborted => ABORTED_EXIT_CODE,
+        };
+    }
+}
+
+impl Report {
+    pub fn new(result: Value) -> Self {
+        return Self {
+            exit_code: SUCCESS_EXIT_CODE,
+            result,
+        };
+    }
+
+    pub fn with_exit_code(mut self, exit_code: i32) -> Self {
+        self.exit_code = exit_code;
+        return self;
+    }
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        return Self { format };
+    }
+
+    pub fn is_json(&self) -> bool {
+        return self.format == OutputFormat::Json;
+    }
+
+    /// Prints a line of human readable output, nothing is printed in JSON mode.
+    pub fn line<D: Display>(&self, line: D) {
+        if !self.is_json() {
+            println!("{}", line);
+        }
+    }
+
+    /// Prints the outcome of a command and returns the code the process should exit with.
+    pub fn finish(&self, outcome: Result<Report, CommandError>) -> i32 {
+        let exit_code = match &outcome {
+            Ok(report) => report.exit_code,
+            Err(e) => e.exit_code(),
+        };
+
+        if self.is_json() {
+            let document = match outcome {
+                Ok(report) => json!({
+                    "success": exit_code == SUCCESS_EXIT_CODE,
+                    "exit_code": exit_code,
+                    "result": report.result,
+                }),
+                Err(e) => json!({
+                    "success": false,
+                    "exit_code": exit_code,
+                    "error": error_json(&e),
+                }),
+            };
+
+            // Serializing a Value cannot fail
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&document).unwrap_or_default()
+            );
+        } else if let Err(e) = outcome {
+            match e {
+                CommandError::Package(e) => print_error(&e),
+                CommandError::Usage(msg) => eprintln!("Error: {}", msg),
+                CommandError::Aborted => eprintln!("Aborting"),
+            }
+        }
+
+        return exit_code;
+    }
+}
+
+pub fn error_json(e: &CommandError) -> Value {
+    return match e {
+        CommandError::Package(e) => json!({
+            "type": format!("{:?}", e.error_type()),
+            "name": e.error_type().to_string(),
+            "description": e.description(),
+            "path": e.path().map(|p| p.display().to_string()),
+            "entry": e.entry(),
+        }),
+        CommandError::Usage(msg) => json!({
+            "type": "UsageError",
+            "name": "Usage Error",
+            "description": msg,
+        }),
+        CommandError::Aborted => json!({
+            "type": "Aborted",
+            "name": "Aborted",
+            "description": "The operation was cancelled",
+        }),
+    };
+}
+
+pub fn error_exit_code(tp: APMErrorType) -> i32 {
+    return match tp {
+        APMErrorType::FileOpenError
+        | APMErrorType::FileReadError
+        | APMErrorType::FileWriteError
+        | APMErrorType::FileExistsError
+        | APMErrorType::DirectoryCreationError
+        | APMErrorType::TempFileCreationError
+        | APMErrorType::WalkdirError
+        | APMErrorType::SymlinkFoundError
+        | APMErrorType::CannotProcessFileNameError => IO_EXIT_CODE,
+        APMErrorType::ZIPOpenError
+        | APMErrorType::ZIPArchiveOpenError
+        | APMErrorType::ZIPCreationError
+        | APMErrorType::ZIPFinishError
+        | APMErrorType::ZIPModificationError
+        | APMErrorType::ZIPArchiveReadError
+        | APMErrorType::ZIPAddDirectoryError
+        | APMErrorType::ZIPStartFileError
+        | APMErrorType::ZIPFileReadError
+        | APMErrorType::ZIPFileCopyError
+        | APMErrorType::ZIPFileWriteError
+        | APMErrorType::UnsafeEntryPathError => ZIP_EXIT_CODE,
+        APMErrorType::ZIPArchiveHiddenNotFoundError => CHECKSUM_MISSING_EXIT_CODE,
+        APMErrorType::HashUTF8Error => CHECKSUM_MISMATCH_EXIT_CODE,
+        APMErrorType::StoreCreationError
+        | APMErrorType::StoreIndexReadError
+        | APMErrorType::StoreIndexWriteError
+        | APMErrorType::StoreLocationError
+        | APMErrorType::PackageNotFoundError
+        | APMErrorType::PackageRemoveError
+        | APMErrorType::MetadataReadError
+        | APMErrorType::MetadataWriteError
+        | APMErrorType::BlobReadError
+        | APMErrorType::BlobWriteError
+        | APMErrorType::ManifestReadError
+        | APMErrorType::ManifestWriteError => STORE_EXIT_CODE,
+        APMErrorType::InvalidPackageNameError
+        | APMErrorType::InvalidTimestampError
+        | APMErrorType::ChecksumExistsError
+        | APMErrorType::InvalidIgnorePatternError
+        | APMErrorType::UnsupportedCompressionError
+        | APMErrorType::InvalidVersionError
+        | APMErrorType::InvalidMetadataError => USAGE_EXIT_CODE,
+        APMErrorType::JSONSerializeError => GENERAL_EXIT_CODE,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        // The codes documented in the README, they are part of the command line interface.
+        let codes = [
+            (APMErrorType::FileOpenError, 5),
+            (APMErrorType::FileReadError, 5),
+            (APMErrorType::FileWriteError, 5),
+            (APMErrorType::ZIPOpenError, 6),
+            (APMErrorType::ZIPArchiveOpenError, 6),
+            (APMErrorType::ZIPCreationError, 6),
+            (APMErrorType::ZIPFinishError, 6),
+            (APMErrorType::ZIPModificationError, 6),
+            (APMErrorType::ZIPArchiveHiddenNotFoundError, 3),
+            (APMErrorType::ZIPArchiveReadError, 6),
+            (APMErrorType::ZIPAddDirectoryError, 6),
+            (APMErrorType::ZIPStartFileError, 6),
+            (APMErrorType::ZIPFileReadError, 6),
+            (APMErrorType::ZIPFileCopyError, 6),
+            (APMErrorType::ZIPFileWriteError, 6),
+            (APMErrorType::HashUTF8Error, 2),
+            (APMErrorType::WalkdirError, 5),
+            (APMErrorType::SymlinkFoundError, 5),
+            (APMErrorType::CannotProcessFileNameError, 5),
+            (APMErrorType::StoreCreationError, 7),
+            (APMErrorType::StoreIndexReadError, 7),
+            (APMErrorType::StoreIndexWriteError, 7),
+            (APMErrorType::StoreLocationError, 7),
+            (APMErrorType::InvalidPackageNameError, 4),
+            (APMErrorType::PackageNotFoundError, 7),
+            (APMErrorType::PackageRemoveError, 7),
+            (APMErrorType::JSONSerializeError, 1),
+            (APMErrorType::TempFileCreationError, 5),
+            (APMErrorType::InvalidTimestampError, 4),
+            (APMErrorType::DirectoryCreationError, 5),
+            (APMErrorType::FileExistsError, 5),
+            (APMErrorType::UnsafeEntryPathError, 6),
+            (APMErrorType::ChecksumExistsError, 4),
+            (APMErrorType::InvalidIgnorePatternError, 4),
+            (APMErrorType::UnsupportedCompressionError, 4),
+            (APMErrorType::InvalidVersionError, 4),
+            (APMErrorType::MetadataReadError, 7),
+            (APMErrorType::MetadataWriteError, 7),
+            (APMErrorType::InvalidMetadataError, 4),
+            (APMErrorType::BlobReadError, 7),
+            (APMErrorType::BlobWriteError, 7),
+            (APMErrorType::ManifestReadError, 7),
+            (APMErrorType::ManifestWriteError, 7),
+        ];
+
+        for (tp, code) in codes {
+            assert_eq!(error_exit_code(tp), code, "{:?}", tp);
+            assert_eq!(
+                CommandError::from(tp.into_apm_error(String::new())).exit_code(),
+                code
+            );
+        }
+
+        assert_eq!(CommandError::Usage(String::new()).exit_code(), 4);
+        assert_eq!(CommandError::Aborted.exit_code(), 8);
+    }
+}
@@ -1,15 +1,250 @@
-use std::io::{stdin, stdout, Write};
+use archer_package_manager::error::APMError;
+use std::io::{stderr, stdin, BufRead, IsTerminal, Write};
+use std::path::Path;
 
+use crate::output::CommandError;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OverwriteMode {
+    /// Ask before overwriting, refusing when there is nobody to ask.
+    Prompt,
+    Always,
+    Never,
+}
+
+impl OverwriteMode {
+    /// The mode chosen by `--force` (or its alias `--yes`) and `--no-clobber`, which never
+    /// overwrites even if both were somehow given.
+    pub fn from_flags(force: bool, no_clobber: bool) -> Self {
+        if no_clobber {
+            return OverwriteMode::Never;
+        }
+
+        if force {
+            return OverwriteMode::Always;
+        }
+
+        return OverwriteMode::Prompt;
+    }
+}
+
+/// Prompts on stderr so JSON output on stdout is left untouched, returns false if stdin closes.
 pub fn y_n_question(question: &str) -> bool {
+    return read_answer(question, &mut stdin().lock(), &mut stderr());
+}
+
+fn read_answer<R: BufRead, W: Write>(question: &str, input: &mut R, prompt: &mut W) -> bool {
     let mut s = String::new();
 
     while s != "y" && s != "n" {
-        print!("{} (y/n) ", question);
-        stdout().flush().unwrap();
+        let _ = write!(prompt, "{} (y/n) ", question);
+        let _ = prompt.flush();
 
-        stdin().read_line(&mut s).unwrap();
-        s = s.trim().to_string();
+        s.clear();
+
+        match input.read_line(&mut s) {
+            Ok(0) | Err(_) => return false,
+            Ok(_) => s = s.trim().to_string(),
+        }
     }
 
     return s == "y";
 }
+
+/// Checks whether `dest` may be written, asking the user if it already exists. Refusing to
+/// overwrite it is a usage error.
+pub fn confirm_overwrite(mode: OverwriteMode, dest: &str) -> Result<(), CommandError> {
+    return check_overwrite(mode, dest, stdin().is_terminal(), y_n_question);
+}
+
+// `confirm_overwrite` with whether there is a terminal to ask and how to ask passed in.
+fn check_overwrite<F>(
+    mode: OverwriteMode,
+    dest: &str,
+    is_terminal: bool,
+    ask: F,
+) -> Result<(), CommandError>
+where
+    F: FnOnce(&str) -> bool,
+{
+    if !Path::new(dest).exists() || mode == OverwriteMode::Always {
+        return Ok(());
+    }
+
+    if mode == OverwriteMode::Never {
+        return Err(CommandError::Usage(format!(
+            "There already exists a file at {} and --no-clobber was given",
+            dest
+        )));
+    }
+
+    if !is_terminal {
+        return Err(CommandError::Usage(format!(
+            "There already exists a file at {}, use --force to overwrite it",
+            dest
+        )));
+    }
+
+    if !ask(&format!(
+        "There already exists a file at {}\nOverwrite?",
+        dest
+    )) {
+        return Err(CommandError::Aborted);
+    }
+
+    return Ok(());
+}
+
+/// Checks whether a batch of `count` packages may be modified in place.
+pub fn confirm_batch(mode: OverwriteMode, count: usize) -> Result<(), CommandError> {
+    return check_batch(mode, count, stdin().is_terminal(), y_n_question);
+}
+
+fn check_batch<F>(
+    mode: OverwriteMode,
+    count: usize,
+    is_terminal: bool,
+    ask: F,
+) -> Result<(), CommandError>
+where
+    F: FnOnce(&str) -> bool,
+{
+    match mode {
+        OverwriteMode::Always => return Ok(()),
+        OverwriteMode::Never => {
+            return Err(CommandError::Usage(
+                "Packages are modified in place and --no-clobber was given".to_string(),
+            ))
+        }
+        OverwriteMode::Prompt => (),
+    }
+
+    if !is_terminal {
+        return Err(CommandError::Usage(
+            "Packages are modified in place, use --force to allow it".to_string(),
+        ));
+    }
+
+    if !ask(&format!("Modify {} packages in place?", count)) {
+        return Err(CommandError::Aborted);
+    }
+
+    return Ok(());
+}
+
+pub fn print_error(e: &APMError) {
+    eprintln!("Error - {}", e.error_type());
+    eprintln!("Description: {}", e.description());
+
+    if let Some(path) = e.path() {
+        eprintln!("File: {}", path.display());
+    }
+
+    if let Some(entry) = e.entry() {
+        eprintln!("Entry: {}", entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::CLIArgs;
+    use clap::Parser;
+
+    // Refusing to overwrite is a usage error, not a file system failure
+    fn is_refused(result: Result<(), CommandError>) -> bool {
+        return matches!(result, Err(CommandError::Usage(_)));
+    }
+
+    #[test]
+    fn test_read_answer() {
+        let mut prompt = Vec::new();
+        assert!(read_answer(
+            "Continue?",
+            &mut &b"maybe\ny\n"[..],
+            &mut prompt
+        ));
+        assert_eq!(prompt, b"Continue? (y/n) Continue? (y/n) ");
+
+        assert!(!read_answer("Continue?", &mut &b"n\n"[..], &mut Vec::new()));
+        // A closed stdin is a no rather than a question asked forever.
+        assert!(!read_answer("Continue?", &mut &b""[..], &mut Vec::new()));
+        assert!(!read_answer(
+            "Continue?",
+            &mut &b"maybe\n"[..],
+            &mut Vec::new()
+        ));
+    }
+
+    #[test]
+    fn test_check_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("existing.zip");
+        let missing = dir.path().join("missing.zip").display().to_string();
+        std::fs::write(&existing, "").unwrap();
+        let existing = existing.display().to_string();
+        let never_asked = |_: &str| -> bool { panic!("The user was asked") };
+
+        for mode in [
+            OverwriteMode::Prompt,
+            OverwriteMode::Always,
+            OverwriteMode::Never,
+        ] {
+            assert!(check_overwrite(mode, &missing, false, never_asked).is_ok());
+        }
+
+        assert!(check_overwrite(OverwriteMode::Always, &existing, false, never_asked).is_ok());
+        assert!(is_refused(check_overwrite(
+            OverwriteMode::Never,
+            &existing,
+            true,
+            never_asked
+        )));
+
+        // Without a terminal nobody can be asked, so it fails rather than waiting.
+        assert!(is_refused(check_overwrite(
+            OverwriteMode::Prompt,
+            &existing,
+            false,
+            never_asked
+        )));
+        assert!(check_overwrite(OverwriteMode::Prompt, &existing, true, |_| true).is_ok());
+        assert!(matches!(
+            check_overwrite(OverwriteMode::Prompt, &existing, true, |_| false),
+            Err(CommandError::Aborted)
+        ));
+
+        assert!(check_batch(OverwriteMode::Always, 2, false, never_asked).is_ok());
+        assert!(is_refused(check_batch(
+            OverwriteMode::Never,
+            2,
+            true,
+            never_asked
+        )));
+        assert!(is_refused(check_batch(
+            OverwriteMode::Prompt,
+            2,
+            false,
+            never_asked
+        )));
+        assert!(check_batch(OverwriteMode::Prompt, 2, true, |q| q.contains('2')).is_ok());
+    }
+
+    #[test]
+    fn test_overwrite_flags() {
+        let mode = |args: &[&str]| {
+            let args = CLIArgs::try_parse_from(["apm"].iter().chain(args).chain(&["man", "list"]))?;
+
+            return Ok::<_, clap::Error>(OverwriteMode::from_flags(args.force, args.no_clobber));
+        };
+
+        assert_eq!(mode(&[]).unwrap(), OverwriteMode::Prompt);
+        assert_eq!(mode(&["--force"]).unwrap(), OverwriteMode::Always);
+        assert_eq!(mode(&["--yes"]).unwrap(), OverwriteMode::Always);
+        assert_eq!(mode(&["-y"]).unwrap(), OverwriteMode::Always);
+        assert_eq!(mode(&["--no-clobber"]).unwrap(), OverwriteMode::Never);
+        assert!(mode(&["--yes", "--no-clobber"]).is_err());
+
+        assert_eq!(OverwriteMode::from_flags(true, true), OverwriteMode::Never);
+    }
+}
@@ -1,3 +1,9 @@
+#![allow(clippy::needless_return)]
+
+pub mod blobs;
+pub mod datetime;
 pub mod error;
 pub mod packages;
+pub mod store;
+pub mod text_diff;
 pub mod zip_manipulation;
@@ -1,180 +1,1900 @@
 use crate::error::{APMError, APMErrorType};
-use crate::zip_manipulation::read_archive;
+use crate::text_diff::unified_diff;
+use crate::zip_manipulation::{
+    append_entry, appended_entry_layout, compress_directory, AppendedEntryLayout, CompressOptions,
+    CompressSummary, CompressionSettings,
+};
 
+use serde::Serialize;
 use sha2::{Digest, Sha256};
-use std::fs::OpenOptions;
-use std::io::{Cursor, Read, Write};
-use zip::{ZipArchive, ZipWriter};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{copy, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use tempfile::NamedTempFile;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
 
-const HIDDEN_FILE_PATH: &'static str = "/hidden";
+pub const HIDDEN_FILE_PATH: &str = "/hidden";
+pub const BACKUP_EXTENSION: &str = "bak";
+const MAX_TEXT_DIFF_SIZE: u64 = 4 * 1024 * 1024;
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+pub trait WriteSeek: Write + Seek {}
+
+impl<T: Write + Seek> WriteSeek for T {}
 
 pub struct Package {
+    source_path: Option<PathBuf>,
+    data: PackageData,
     contents: Option<PackageContents>,
-    source_path: String,
+    modified: bool,
+}
+
+// Where the bytes of a package currently live. Modified packages are written to temporary files so
+// that memory use does not grow with the size of the package.
+enum PackageData {
+    File(PathBuf),
+    Temp(NamedTempFile),
+    Memory(Vec<u8>),
+}
+
+pub struct PackageContents {
+    entries: Vec<PackageEntry>,
+    has_checksum_entry: bool,
+    stored_checksum: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ChecksumStatus {
+    Valid(String),
+    Mismatch { stored: String, computed: String },
+    Missing { computed: String },
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct PackageInfo {
+    pub source_path: Option<String>,
+    pub file_size: u64,
+    pub entry_count: usize,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    pub compression_methods: BTreeMap<String, usize>,
+    pub stored_checksum: Option<String>,
+    pub computed_checksum: String,
+    pub checksum_valid: bool,
+    pub entries: Vec<EntryInfo>,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct EntryInfo {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub compressed_size: u64,
+    pub compression: String,
+    pub last_modified: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct PackageEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub compressed_size: u64,
+    pub compression: CompressionMethod,
+    pub last_modified: DateTime,
+    pub unix_mode: Option<u32>,
+    pub crc32: u32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct DiffOptions {
+    /// Compare the `/hidden` checksum entry as well, it differs whenever anything else does.
+    pub include_checksum: bool,
+    /// Produce a unified diff for modified entries which contain text.
+    pub text_diff: bool,
+    pub context_lines: usize,
+}
+
+#[derive(Serialize, Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct EntryChange {
+    pub name: String,
+    pub kind: ChangeKind,
+    pub is_dir: bool,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+    pub size_delta: i64,
+    pub text_diff: Option<String>,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct PackageDiff {
+    pub old_size: u64,
+    pub new_size: u64,
+    pub size_delta: i64,
+    pub changes: Vec<EntryChange>,
+}
+
+/// What to do when an extracted file already exists.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub enum OverwritePolicy {
+    #[default]
+    Error,
+    Skip,
+    Overwrite,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ExtractOptions {
+    pub overwrite: OverwritePolicy,
+    /// Writes the stored checksum to this file, it is discarded otherwise.
+    pub checksum_path: Option<PathBuf>,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ExtractSummary {
+    pub extracted: Vec<String>,
+    pub skipped: Vec<String>,
+    pub checksum: Option<String>,
+}
+
+impl Package {
+    /// Creates a package backed by the file at `path`, the file is not read until it is needed.
+    pub fn open(path: &str) -> Result<Self, APMError> {
+        let p = Path::new(path);
+
+        if !p.is_file() {
+            return Err(APMErrorType::FileOpenError
+                .into_apm_error(format!("There is no file at {}", path))
+                .with_path(p));
+        }
+
+        return Ok(Self {
+            source_path: Some(p.to_path_buf()),
+            data: PackageData::File(p.to_path_buf()),
+            contents: None,
+            modified: false,
+        });
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        return Self {
+            source_path: None,
+            data: PackageData::Memory(bytes),
+            contents: None,
+            modified: false,
+        };
+    }
+
+    /// Compresses `dir` into a new package backed by a temporary file.
+    pub fn from_directory(
+        dir: &str,
+        options: &CompressOptions,
+    ) -> Result<(Self, CompressSummary), APMError> {
+        return Self::from_writer(|writer| compress_directory(dir, options, writer));
+    }
+
+    /// Creates a package backed by a temporary file from the zip `write` produces.
+    pub fn from_writer<F, T>(write: F) -> Result<(Self, T), APMError>
+    where
+        F: FnOnce(&mut dyn WriteSeek) -> Result<T, APMError>,
+    {
+        let mut temp = new_temp_file()?;
+        let result;
+
+        {
+            let mut writer = BufWriter::new(temp.as_file_mut());
+
+            result = write(&mut writer)?;
+
+            writer
+                .flush()
+                .map_err(|e| APMErrorType::FileWriteError.from_error(e))?;
+        }
+
+        let package = Self {
+            source_path: None,
+            data: PackageData::Temp(temp),
+            contents: None,
+            modified: true,
+        };
+
+        return Ok((package, result));
+    }
+
+    pub fn source_path(&self) -> Option<&Path> {
+        return self.source_path.as_deref();
+    }
+
+    /// Whether the package has been changed since it was opened.
+    pub fn is_modified(&self) -> bool {
+        return self.modified;
+    }
+
+    /// Opens a reader over the current bytes of the package.
+    pub fn reader(&self) -> Result<Box<dyn ReadSeek + '_>, APMError> {
+        return match &self.data {
+            PackageData::File(p) => Ok(Box::new(open_file(p)?)),
+            PackageData::Temp(t) => Ok(Box::new(
+                t.reopen()
+                    .map_err(|e| APMErrorType::FileOpenError.from_error(e))?,
+            )),
+            PackageData::Memory(b) => Ok(Box::new(Cursor::new(b.as_slice()))),
+        };
+    }
+
+    /// Copies the bytes of the package into `writer`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<u64, APMError> {
+        return copy(&mut self.reader()?, writer)
+            .map_err(|e| APMErrorType::FileWriteError.from_error(e));
+    }
+
+    /// The size in bytes of the package file.
+    pub fn size(&self) -> Result<u64, APMError> {
+        return match &self.data {
+            PackageData::File(p) => fs::metadata(p)
+                .map(|m| m.len())
+                .map_err(|e| APMErrorType::FileReadError.from_error(e)),
+            PackageData::Temp(t) => t
+                .as_file()
+                .metadata()
+                .map(|m| m.len())
+                .map_err(|e| APMErrorType::FileReadError.from_error(e)),
+            PackageData::Memory(b) => Ok(b.len() as u64),
+        };
+    }
+
+    pub fn contents(&mut self) -> Result<&PackageContents, APMError> {
+        if self.contents.is_none() {
+            let contents = PackageContents::parse(self.reader()?)?;
+
+            self.contents = Some(contents);
+        }
+
+        return Ok(self.contents.as_ref().unwrap());
+    }
+
+    pub fn entries(&mut self) -> Result<&[PackageEntry], APMError> {
+        return Ok(self.contents()?.entries());
+    }
+
+    pub fn stored_checksum(&mut self) -> Result<Option<&str>, APMError> {
+        return Ok(self.contents()?.stored_checksum());
+    }
+
+    /// Calculates the Archer checksum of the package as it would be without a `/hidden` entry.
+    pub fn computed_checksum(&mut self) -> Result<String, APMError> {
+        if !self.contents()?.has_checksum_entry() {
+            return Ok(base64::encode(hash_reader(&mut self.reader()?)?));
+        }
+
+        // An appended checksum can be removed exactly, otherwise fall back to a rewrite.
+        if let Some(layout) = self.appended_checksum_layout()? {
+            let reader = self.reader()?;
+            let mut hasher = Sha256::new();
+
+            copy(&mut reader.take(layout.prefix_len), &mut hasher)
+                .map_err(|e| APMErrorType::FileReadError.from_error(e))?;
+            hasher.update(&layout.tail);
+
+            return Ok(base64::encode(hasher.finalize()));
+        }
+
+        let (stripped, _) =
+            self.write_new_data(|output| strip_checksum(&mut self.archive()?, output))?;
+        let stripped = Package {
+            source_path: None,
+            data: stripped,
+            contents: None,
+            modified: true,
+        };
+
+        return Ok(base64::encode(hash_reader(&mut stripped.reader()?)?));
+    }
+
+    /// Compares the checksum stored in `/hidden` with one computed from the package's contents.
+    pub fn verify(&mut self) -> Result<ChecksumStatus, APMError> {
+        let computed = self.computed_checksum()?;
+
+        return Ok(match self.stored_checksum()? {
+            None => ChecksumStatus::Missing { computed },
+            Some(stored) if stored == computed => ChecksumStatus::Valid(computed),
+            Some(stored) => ChecksumStatus::Mismatch {
+                stored: stored.to_string(),
+                computed,
+            },
+        });
+    }
+
+    /// Collects a summary of the package, including whether its checksum is valid.
+    pub fn info(&mut self) -> Result<PackageInfo, APMError> {
+        let status = self.verify()?;
+        let file_size = self.size()?;
+        let source_path = self.source_path().map(|p| p.display().to_string());
+        let contents = self.contents()?;
+        let mut compression_methods = BTreeMap::new();
+
+        for entry in contents.entries().iter().filter(|e| !e.is_dir) {
+            *compression_methods
+                .entry(entry.compression.to_string())
+                .or_insert(0) += 1;
+        }
+
+        let (computed_checksum, checksum_valid) = match status {
+            ChecksumStatus::Valid(computed) => (computed, true),
+            ChecksumStatus::Mismatch { computed, .. } => (computed, false),
+            ChecksumStatus::Missing { computed } => (computed, false),
+        };
+
+        return Ok(PackageInfo {
+            source_path,
+            file_size,
+            entry_count: contents.len(),
+            compressed_size: contents.compressed_size(),
+            uncompressed_size: contents.uncompressed_size(),
+            compression_methods,
+            stored_checksum: contents.stored_checksum().map(|s| s.to_string()),
+            computed_checksum,
+            checksum_valid,
+            entries: contents
+                .entries()
+                .iter()
+                .map(|e| EntryInfo {
+                    name: e.name.clone(),
+                    is_dir: e.is_dir,
+                    size: e.size,
+                    compressed_size: e.compressed_size,
+                    compression: e.compression.to_string(),
+                    last_modified: format_zip_datetime(&e.last_modified),
+                })
+                .collect(),
+        });
+    }
+
+    /// Removes the `/hidden` entry, returning whether one was found. Every other entry is copied
+    /// without being decompressed so its data, compression method, timestamp and permissions are
+    /// unchanged.
+    pub fn remove_checksum(&mut self) -> Result<bool, APMError> {
+        if !self.contents()?.has_checksum_entry() {
+            return Ok(false);
+        }
+
+        // An appended checksum can be removed by truncating the archive, which restores it exactly.
+        if let Some(layout) = self.appended_checksum_layout()? {
+            let mut reader = self.reader()?;
+            let (stripped, _) =
+                self.write_new_data(|output| write_stripped(&mut reader, &layout, output))?;
+
+            drop(reader);
+            self.replace_data(stripped);
+
+            return Ok(true);
+        }
+
+        let (stripped, removed) =
+            self.write_new_data(|output| strip_checksum(&mut self.archive()?, output))?;
+
+        self.replace_data(stripped);
+
+        return Ok(removed);
+    }
+
+    /// Rewrites every entry with the method `settings` choose for it, keeping names, timestamps and
+    /// permissions. The checksum entry no longer matches afterwards so it is dropped, returns
+    /// whether there was one.
+    pub fn recompress(&mut self, settings: &CompressionSettings) -> Result<bool, APMError> {
+        settings.validate()?;
+
+        let (data, removed) = self
+            .write_new_data(|output| recompress_archive(&mut self.archive()?, settings, output))?;
+
+        self.replace_data(data);
+
+        return Ok(removed);
+    }
+
+    /// Calculates the checksum of the package and appends it as the `/hidden` entry. Packages which
+    /// already have a checksum entry are refused, see `replace_checksum`.
+    pub fn add_checksum(&mut self) -> Result<String, APMError> {
+        return self.add_checksum_with_options(FileOptions::default());
+    }
+
+    /// Adds the checksum entry using `options`, e.g. the options of a reproducible build.
+    pub fn add_checksum_with_options(&mut self, options: FileOptions) -> Result<String, APMError> {
+        if self.contents()?.has_checksum_entry() {
+            let e = APMErrorType::ChecksumExistsError
+                .into_apm_error("The package already has a checksum entry".to_string())
+                .with_entry(HIDDEN_FILE_PATH);
+
+            return Err(match &self.source_path {
+                Some(p) => e.with_path(p),
+                None => e,
+            });
+        }
+
+        let hash_string = base64::encode(hash_reader(&mut self.reader()?)?);
+
+        self.append_checksum(&hash_string, options)?;
+
+        return Ok(hash_string);
+    }
+
+    /// Adds the checksum entry, replacing an existing one so the package ends up with exactly one.
+    pub fn replace_checksum(&mut self) -> Result<String, APMError> {
+        return self.replace_checksum_with_options(FileOptions::default());
+    }
+
+    pub fn replace_checksum_with_options(
+        &mut self,
+        options: FileOptions,
+    ) -> Result<String, APMError> {
+        if !self.contents()?.has_checksum_entry() {
+            return self.add_checksum_with_options(options);
+        }
+
+        // An appended checksum is stripped and the result hashed while it is written, so the
+        // package is only read once.
+        if let Some(layout) = self.appended_checksum_layout()? {
+            let mut reader = self.reader()?;
+            let (stripped, hash) = self.write_new_data(|output| {
+                let mut writer = HashingWriter::new(output);
+
+                write_stripped(&mut reader, &layout, &mut writer)?;
+
+                return Ok(writer.finalize());
+            })?;
+
+            drop(reader);
+            self.replace_data(stripped);
+
+            let hash_string = base64::encode(hash);
+
+            self.append_checksum(&hash_string, options)?;
+
+            return Ok(hash_string);
+        }
+
+        self.remove_checksum()?;
+
+        return self.add_checksum_with_options(options);
+    }
+
+    /// How to restore the package as it was before its `/hidden` entry was appended, if it was.
+    /// When the central directory may have been rewritten by an older version of apm the layout
+    /// which matches the stored checksum is used.
+    fn appended_checksum_layout(&mut self) -> Result<Option<AppendedEntryLayout>, APMError> {
+        let stored = self.stored_checksum()?.map(|s| s.to_string());
+        let mut reader = self.reader()?;
+
+        let layout = match appended_entry_layout(&mut reader, HIDDEN_FILE_PATH)? {
+            Some(layout) => layout,
+            None => return Ok(None),
+        };
+        let legacy_tail = match (&layout.legacy_tail, &stored) {
+            (Some(legacy_tail), Some(_)) => legacy_tail,
+            _ => return Ok(Some(layout)),
+        };
+
+        reader
+            .seek(SeekFrom::Start(0))
+            .map_err(|e| APMErrorType::FileReadError.from_error(e))?;
+
+        let mut prefix = Sha256::new();
+
+        copy(&mut reader.take(layout.prefix_len), &mut prefix)
+            .map_err(|e| APMErrorType::FileReadError.from_error(e))?;
+
+        let checksum_with = |tail: &[u8]| {
+            let mut hasher = prefix.clone();
+            hasher.update(tail);
+
+            return Some(base64::encode(hasher.finalize()));
+        };
+
+        if checksum_with(&layout.tail) != stored && checksum_with(legacy_tail) == stored {
+            return Ok(Some(AppendedEntryLayout {
+                prefix_len: layout.prefix_len,
+                tail: legacy_tail.clone(),
+                legacy_tail: None,
+            }));
+        }
+
+        return Ok(Some(layout));
+    }
+
+    fn append_checksum(&mut self, hash_string: &str, options: FileOptions) -> Result<(), APMError> {
+        let mut data = self.take_writable_data()?;
+        let contents = hash_string.as_bytes();
+
+        let appended = match &mut data {
+            PackageData::Memory(b) => {
+                append_entry(&mut Cursor::new(b), HIDDEN_FILE_PATH, contents, options)?
+            }
+            PackageData::Temp(t) => {
+                append_entry(t.as_file_mut(), HIDDEN_FILE_PATH, contents, options)?
+            }
+            PackageData::File(_) => unreachable!(),
+        };
+
+        // Archives the central directory cannot be copied from, such as ZIP64 ones, are appended
+        // to by the zip crate.
+        if !appended {
+            let mut zip_writer = match &mut data {
+                PackageData::Memory(b) => {
+                    ZipWriter::new_append(Box::new(Cursor::new(b)) as Box<dyn ReadWriteSeek>)
+                }
+                PackageData::Temp(t) => {
+                    ZipWriter::new_append(Box::new(t.as_file_mut()) as Box<dyn ReadWriteSeek>)
+                }
+                PackageData::File(_) => unreachable!(),
+            }
+            .map_err(|e| APMErrorType::ZIPOpenError.from_error(e))?;
+
+            zip_writer
+                .start_file(HIDDEN_FILE_PATH, options)
+                .map_err(|e| APMErrorType::ZIPModificationError.from_error(e))?;
+
+            zip_writer
+                .write_all(contents)
+                .map_err(|e| APMErrorType::ZIPModificationError.from_error(e))?;
+
+            zip_writer
+                .finish()
+                .map_err(|e| APMErrorType::ZIPFinishError.from_error(e))?;
+        }
+
+        self.replace_data(data);
+
+        return Ok(());
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), APMError> {
+        self.save_with_backup(path, false)?;
+
+        return Ok(());
+    }
+
+    /// Saves the package to `path` without ever leaving a partially written file there. With
+    /// `backup` the file being replaced is kept as `<path>.bak`, whose path is returned.
+    pub fn save_with_backup(&self, path: &str, backup: bool) -> Result<Option<PathBuf>, APMError> {
+        if let PackageData::File(source) = &self.data {
+            // An unmodified package saved over itself is already there.
+            if is_same_file(source, Path::new(path)) {
+                return Ok(None);
+            }
+        }
+
+        return write_atomically(Path::new(path), backup, |f| {
+            self.write_to(f)?;
+            return Ok(());
+        });
+    }
+
+    pub fn extract(
+        &self,
+        dest: &str,
+        options: &ExtractOptions,
+    ) -> Result<ExtractSummary, APMError> {
+        return extract_package(self.reader()?, dest, options);
+    }
+
+    fn read_entry(&self, name: &str) -> Result<Vec<u8>, APMError> {
+        let mut archive = self.archive()?;
+        let mut f = archive.by_name(name).map_err(|e| {
+            APMErrorType::ZIPArchiveReadError
+                .from_error(e)
+                .with_entry(name)
+        })?;
+        let mut bytes = Vec::with_capacity(f.size() as usize);
+
+        f.read_to_end(&mut bytes).map_err(|e| {
+            APMErrorType::ZIPFileReadError
+                .from_error(e)
+                .with_entry(name)
+        })?;
+
+        return Ok(bytes);
+    }
+
+    fn archive(&self) -> Result<ZipArchive<Box<dyn ReadSeek + '_>>, APMError> {
+        return ZipArchive::new(self.reader()?)
+            .map_err(|e| APMErrorType::ZIPArchiveOpenError.from_error(e));
+    }
+
+    // Runs `write` against a new output, the output is kept in memory if the package already is,
+    // otherwise it goes to a temporary file.
+    fn write_new_data<F, T>(&self, write: F) -> Result<(PackageData, T), APMError>
+    where
+        F: FnOnce(&mut dyn WriteSeek) -> Result<T, APMError>,
+    {
+        if let PackageData::Memory(_) = self.data {
+            let mut cursor = Cursor::new(Vec::new());
+            let result = write(&mut cursor)?;
+
+            return Ok((PackageData::Memory(cursor.into_inner()), result));
+        }
+
+        let mut temp = new_temp_file()?;
+        let result;
+
+        {
+            let mut writer = BufWriter::new(temp.as_file_mut());
+
+            result = write(&mut writer)?;
+
+            writer
+                .flush()
+                .map_err(|e| APMErrorType::FileWriteError.from_error(e))?;
+        }
+
+        return Ok((PackageData::Temp(temp), result));
+    }
+
+    // Takes the package data in a form which can be modified in place, packages backed by a file
+    // which apm does not own are copied to a temporary file first.
+    fn take_writable_data(&mut self) -> Result<PackageData, APMError> {
+        if let PackageData::File(p) = &self.data {
+            let mut temp = new_temp_file()?;
+
+            copy(&mut open_file(p)?, temp.as_file_mut())
+                .map_err(|e| APMErrorType::FileWriteError.from_error(e))?;
+
+            return Ok(PackageData::Temp(temp));
+        }
+
+        return Ok(mem::replace(
+            &mut self.data,
+            PackageData::Memory(Vec::new()),
+        ));
+    }
+
+    fn replace_data(&mut self, data: PackageData) {
+        self.data = data;
+        self.contents = None;
+        self.modified = true;
+    }
 }
 
-pub struct PackageContents {}
+impl PackageContents {
+    fn parse<R: Read + Seek>(reader: R) -> Result<Self, APMError> {
+        let mut archive =
+            ZipArchive::new(reader).map_err(|e| APMErrorType::ZIPArchiveOpenError.from_error(e))?;
+        let mut entries = Vec::with_capacity(archive.len());
+        let mut has_checksum_entry = false;
+        let mut stored_checksum = None;
+
+        for i in 0..archive.len() {
+            let mut f = archive
+                .by_index(i)
+                .map_err(|e| APMErrorType::ZIPArchiveReadError.from_error(e))?;
+
+            if f.name() == HIDDEN_FILE_PATH {
+                let mut hash = Vec::new();
+
+                f.read_to_end(&mut hash)
+                    .map_err(|e| APMErrorType::ZIPArchiveReadError.from_error(e))?;
+
+                has_checksum_entry = true;
+                stored_checksum = Some(String::from_utf8_lossy(&hash).into_owned());
+            }
+
+            entries.push(PackageEntry {
+                name: f.name().to_string(),
+                is_dir: f.is_dir(),
+                size: f.size(),
+                compressed_size: f.compressed_size(),
+                compression: f.compression(),
+                last_modified: f.last_modified(),
+                unix_mode: f.unix_mode(),
+                crc32: f.crc32(),
+            });
+        }
+
+        return Ok(Self {
+            entries,
+            has_checksum_entry,
+            stored_checksum,
+        });
+    }
+
+    pub fn entries(&self) -> &[PackageEntry] {
+        return &self.entries;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    pub fn file_names(&self) -> impl Iterator<Item = &str> {
+        return self.entries.iter().map(|e| e.name.as_str());
+    }
+
+    pub fn has_checksum_entry(&self) -> bool {
+        return self.has_checksum_entry;
+    }
 
-pub fn remove_checksum_zip(path: &str) -> Result<(Vec<u8>, bool), APMError> {
-    let mut archive = read_archive(path)?;
-    let options = zip::write::FileOptions::default();
+    /// The checksum stored in the `/hidden` entry, `None` if there is no such entry.
+    pub fn stored_checksum(&self) -> Option<&str> {
+        return self.stored_checksum.as_deref();
+    }
 
-    let mut output = Vec::new();
-    let mut zip_writer = ZipWriter::new(Cursor::new(&mut output));
+    pub fn uncompressed_size(&self) -> u64 {
+        return self.entries.iter().map(|e| e.size).sum();
+    }
+
+    pub fn compressed_size(&self) -> u64 {
+        return self.entries.iter().map(|e| e.compressed_size).sum();
+    }
+}
+
+fn strip_checksum<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    output: &mut dyn WriteSeek,
+) -> Result<bool, APMError> {
+    let mut zip_writer = ZipWriter::new(output);
     let mut checksum_removed = false;
 
+    zip_writer.set_raw_comment(archive.comment().to_vec());
+
+    for i in 0..archive.len() {
+        let f = archive
+            .by_index_raw(i)
+            .map_err(|e| APMErrorType::ZIPArchiveReadError.from_error(e))?;
+
+        if f.name() == HIDDEN_FILE_PATH {
+            checksum_removed = true;
+            continue;
+        }
+
+        zip_writer
+            .raw_copy_file(f)
+            .map_err(|e| APMErrorType::ZIPFileCopyError.from_error(e))?;
+    }
+
+    zip_writer
+        .finish()
+        .map_err(|e| APMErrorType::ZIPFinishError.from_error(e))?;
+
+    return Ok(checksum_removed);
+}
+
+fn recompress_archive<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    settings: &CompressionSettings,
+    output: &mut dyn WriteSeek,
+) -> Result<bool, APMError> {
+    let mut zip_writer = ZipWriter::new(output);
+    let mut checksum_removed = false;
+
+    zip_writer.set_raw_comment(archive.comment().to_vec());
+
     for i in 0..archive.len() {
         let mut f = archive
             .by_index(i)
-            .map_err(|e| APMErrorType::ZIPArchiveReadError.into_apm_error(e.to_string()))?;
+            .map_err(|e| APMErrorType::ZIPArchiveReadError.from_error(e))?;
+        let name = f.name().to_string();
 
-        if f.name() != HIDDEN_FILE_PATH {
-            if f.is_dir() {
-                zip_writer.add_directory(f.name(), options).map_err(|e| {
-                    APMErrorType::ZIPAddDirectoryError.into_apm_error(e.to_string())
-                })?;
-            } else if f.is_file() {
-                zip_writer
-                    .start_file(f.name(), options)
-                    .map_err(|e| APMErrorType::ZIPStartFileError.into_apm_error(e.to_string()))?;
-                let mut buf = Vec::new();
-
-                f.read_to_end(&mut buf)
-                    .map_err(|e| APMErrorType::ZIPFileReadError.into_apm_error(e.to_string()))?;
-
-                zip_writer
-                    .write_all(&mut buf)
-                    .map_err(|e| APMErrorType::ZIPFileWriteError.into_apm_error(e.to_string()))?;
-            }
-        } else {
+        if name == HIDDEN_FILE_PATH {
             checksum_removed = true;
+            continue;
+        }
+
+        let mut options = FileOptions::default().last_modified_time(f.last_modified());
+
+        if let Some(mode) = f.unix_mode() {
+            options = options.unix_permissions(mode);
+        }
+
+        if f.is_dir() {
+            zip_writer
+                .add_directory(name.as_str(), options)
+                .map_err(|e| {
+                    APMErrorType::ZIPAddDirectoryError
+                        .from_error(e)
+                        .with_entry(&name)
+                })?;
+
+            continue;
         }
+
+        zip_writer
+            .start_file(name.as_str(), settings.apply(options, &name))
+            .map_err(|e| {
+                APMErrorType::ZIPStartFileError
+                    .from_error(e)
+                    .with_entry(&name)
+            })?;
+
+        copy(&mut f, &mut zip_writer).map_err(|e| {
+            APMErrorType::ZIPFileCopyError
+                .from_error(e)
+                .with_entry(&name)
+        })?;
     }
 
     zip_writer
         .finish()
-        .map_err(|e| APMErrorType::ZIPFinishError.into_apm_error(e.to_string()))?;
+        .map_err(|e| APMErrorType::ZIPFinishError.from_error(e))?;
+
+    return Ok(checksum_removed);
+}
+
+fn write_stripped<R: Read + Seek, W: Write + ?Sized>(
+    reader: &mut R,
+    layout: &AppendedEntryLayout,
+    output: &mut W,
+) -> Result<(), APMError> {
+    reader
+        .seek(SeekFrom::Start(0))
+        .map_err(|e| APMErrorType::FileReadError.from_error(e))?;
 
-    drop(zip_writer);
+    copy(&mut reader.take(layout.prefix_len), output)
+        .map_err(|e| APMErrorType::FileWriteError.from_error(e))?;
 
-    return Ok((output, checksum_removed));
+    return output
+        .write_all(&layout.tail)
+        .map_err(|e| APMErrorType::FileWriteError.from_error(e));
 }
 
-pub fn insert_checksum_zip(
-    path: &str,
-    remove_checksum: bool,
-) -> Result<(Vec<u8>, String), APMError> {
-    let mut contents;
+impl Default for DiffOptions {
+    fn default() -> Self {
+        return Self {
+            include_checksum: false,
+            text_diff: true,
+            context_lines: 3,
+        };
+    }
+}
+
+impl PackageDiff {
+    pub fn is_empty(&self) -> bool {
+        return self.changes.is_empty();
+    }
+}
+
+/// Compares two packages entry by entry. Entries are modified when their size or CRC-32 differ,
+/// so changes which only affect compression or timestamps are not reported.
+pub fn diff(
+    old: &mut Package,
+    new: &mut Package,
+    options: &DiffOptions,
+) -> Result<PackageDiff, APMError> {
+    let old_entries: BTreeMap<String, PackageEntry> = old
+        .entries()?
+        .iter()
+        .map(|e| (e.name.clone(), e.clone()))
+        .collect();
+    let new_entries: BTreeMap<String, PackageEntry> = new
+        .entries()?
+        .iter()
+        .map(|e| (e.name.clone(), e.clone()))
+        .collect();
+
+    let mut names: Vec<&String> = old_entries.keys().chain(new_entries.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    let mut changes = Vec::new();
+
+    for name in names {
+        if name == HIDDEN_FILE_PATH && !options.include_checksum {
+            continue;
+        }
+
+        let (kind, is_dir) = match (old_entries.get(name), new_entries.get(name)) {
+            (None, Some(n)) => (ChangeKind::Added, n.is_dir),
+            (Some(o), None) => (ChangeKind::Removed, o.is_dir),
+            (Some(o), Some(n)) => {
+                if o.is_dir == n.is_dir && o.size == n.size && o.crc32 == n.crc32 {
+                    continue;
+                }
+
+                (ChangeKind::Modified, n.is_dir)
+            }
+            (None, None) => continue,
+        };
+
+        let old_size = old_entries.get(name).map(|e| e.size);
+        let new_size = new_entries.get(name).map(|e| e.size);
+        let mut text_diff = None;
+
+        if options.text_diff
+            && kind == ChangeKind::Modified
+            && !is_dir
+            && old_size.unwrap_or(0) <= MAX_TEXT_DIFF_SIZE
+            && new_size.unwrap_or(0) <= MAX_TEXT_DIFF_SIZE
+        {
+            text_diff = entry_text_diff(old, new, name, options.context_lines)?;
+        }
+
+        changes.push(EntryChange {
+            name: name.clone(),
+            kind,
+            is_dir,
+            old_size,
+            new_size,
+            size_delta: new_size.unwrap_or(0) as i64 - old_size.unwrap_or(0) as i64,
+            text_diff,
+        });
+    }
+
+    let old_size = old.size()?;
+    let new_size = new.size()?;
 
-    if remove_checksum {
-        (contents, _) = remove_checksum_zip(path)?;
-    } else {
-        contents = Vec::new();
+    return Ok(PackageDiff {
+        old_size,
+        new_size,
+        size_delta: new_size as i64 - old_size as i64,
+        changes,
+    });
+}
+
+fn entry_text_diff(
+    old: &Package,
+    new: &Package,
+    name: &str,
+    context_lines: usize,
+) -> Result<Option<String>, APMError> {
+    let old_text = match as_text(old.read_entry(name)?) {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    let new_text = match as_text(new.read_entry(name)?) {
+        Some(t) => t,
+        None => return Ok(None),
+    };
 
-        let mut f = OpenOptions::new()
-            .read(true)
-            .write(false)
-            .open(path)
-            .map_err(|e| APMErrorType::FileOpenError.into_apm_error(e.to_string()))?;
+    return Ok(unified_diff(
+        &old_text,
+        &new_text,
+        name,
+        name,
+        context_lines,
+    ));
+}
 
-        f.read_to_end(&mut contents)
-            .map_err(|e| APMErrorType::FileReadError.into_apm_error(e.to_string()))?;
+// Treats entries as text when they are UTF-8 without any NUL bytes, which covers the XML and
+// text files a package is made of while skipping binaries.
+fn as_text(bytes: Vec<u8>) -> Option<String> {
+    if bytes.contains(&0) {
+        return None;
     }
 
-    return add_checksum_zip(contents);
+    return String::from_utf8(bytes).ok();
 }
 
-pub fn add_checksum_zip(mut contents: Vec<u8>) -> Result<(Vec<u8>, String), APMError> {
-    let options = zip::write::FileOptions::default();
+/// Unpacks every entry except `/hidden` into `dest`, the inverse of `compress_directory`.
+///
+/// Entry names which are absolute or contain `..` are rejected before anything is written, and
+/// every file is checked to land inside `dest` so existing symlinks cannot redirect the output.
+pub fn extract_package<R: Read + Seek>(
+    reader: R,
+    dest: &str,
+    options: &ExtractOptions,
+) -> Result<ExtractSummary, APMError> {
+    let mut archive =
+        ZipArchive::new(reader).map_err(|e| APMErrorType::ZIPArchiveOpenError.from_error(e))?;
+    let mut summary = ExtractSummary::default();
+    let mut outputs = Vec::with_capacity(archive.len());
 
-    let hash_bytes = generate_archer_hash_from_bytes(&contents);
-    let hash_string = base64::encode(hash_bytes);
+    // Validate every name up front so a malicious entry cannot leave a partial extraction behind.
+    for i in 0..archive.len() {
+        let f = archive
+            .by_index_raw(i)
+            .map_err(|e| APMErrorType::ZIPArchiveReadError.from_error(e))?;
 
-    let mut zip_writer = ZipWriter::new_append(Cursor::new(&mut contents))
-        .map_err(|e| APMErrorType::ZIPOpenError.into_apm_error(e.to_string()))?;
+        if f.name() == HIDDEN_FILE_PATH {
+            outputs.push(None);
+        } else {
+            outputs.push(Some(entry_output_path(f.name())?));
+        }
+    }
 
-    zip_writer
-        .start_file(HIDDEN_FILE_PATH, options)
-        .map_err(|e| APMErrorType::ZIPModificationError.into_apm_error(e.to_string()))?;
+    let has_checksum = outputs.iter().any(|o| o.is_none());
+    let checksum_path = match (&options.checksum_path, has_checksum) {
+        (Some(path), true) => checksum_output(path, options.overwrite)?,
+        _ => None,
+    };
+    let root = Path::new(dest);
 
-    zip_writer
-        .write_all(hash_string.as_bytes())
-        .map_err(|e| APMErrorType::ZIPModificationError.into_apm_error(e.to_string()))?;
+    fs::create_dir_all(root).map_err(|e| {
+        APMErrorType::DirectoryCreationError
+            .from_error(e)
+            .with_path(dest)
+    })?;
 
-    zip_writer
-        .finish()
-        .map_err(|e| APMErrorType::ZIPFinishError.into_apm_error(e.to_string()))?;
+    let root = root
+        .canonicalize()
+        .map_err(|e| APMErrorType::FileOpenError.from_error(e))?;
 
-    drop(zip_writer);
+    for (i, relative) in outputs.into_iter().enumerate() {
+        let mut f = archive
+            .by_index(i)
+            .map_err(|e| APMErrorType::ZIPArchiveReadError.from_error(e))?;
+
+        let relative = match relative {
+            Some(relative) => relative,
+            None => {
+                let mut checksum = String::new();
+
+                f.read_to_string(&mut checksum).map_err(|e| {
+                    APMErrorType::ZIPFileReadError
+                        .from_error(e)
+                        .with_entry(HIDDEN_FILE_PATH)
+                })?;
+
+                summary.checksum = Some(checksum);
+                continue;
+            }
+        };
+
+        let out_path = root.join(&relative);
+
+        if f.is_dir() {
+            create_dir_inside(&root, &out_path)?;
+            summary.extracted.push(f.name().to_string());
+            continue;
+        }
+
+        if let Some(parent) = out_path.parent() {
+            create_dir_inside(&root, parent)?;
+        }
+
+        if fs::symlink_metadata(&out_path).is_ok() {
+            match options.overwrite {
+                OverwritePolicy::Error => {
+                    return Err(APMErrorType::FileExistsError.into_apm_error(format!(
+                        "There already exists a file at {}",
+                        out_path.display()
+                    )));
+                }
+                OverwritePolicy::Skip => {
+                    summary.skipped.push(f.name().to_string());
+                    continue;
+                }
+                OverwritePolicy::Overwrite => {
+                    // Remove rather than truncate so a symlink is replaced instead of followed.
+                    fs::remove_file(&out_path).map_err(|e| {
+                        APMErrorType::FileWriteError
+                            .from_error(e)
+                            .with_path(&out_path)
+                    })?;
+                }
+            }
+        }
 
-    return Ok((contents, hash_string));
+        let mut out = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&out_path)
+            .map_err(|e| {
+                APMErrorType::FileOpenError
+                    .from_error(e)
+                    .with_path(&out_path)
+            })?;
+
+        copy(&mut f, &mut out).map_err(|e| {
+            APMErrorType::FileWriteError
+                .from_error(e)
+                .with_path(&out_path)
+                .with_entry(f.name())
+        })?;
+
+        #[cfg(unix)]
+        if let Some(mode) = f.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+
+            out.set_permissions(fs::Permissions::from_mode(mode & 0o777))
+                .map_err(|e| {
+                    APMErrorType::FileWriteError
+                        .from_error(e)
+                        .with_path(&out_path)
+                })?;
+        }
+
+        summary.extracted.push(f.name().to_string());
+    }
+
+    if let (Some(checksum_path), Some(checksum)) = (checksum_path, &summary.checksum) {
+        write_atomically(checksum_path, false, |f| {
+            return f.write_all(checksum.as_bytes()).map_err(|e| {
+                APMErrorType::FileWriteError
+                    .from_error(e)
+                    .with_path(checksum_path)
+            });
+        })?;
+    }
+
+    return Ok(summary);
 }
 
-pub fn dump_file_names_zip(path: &str) -> Result<Vec<String>, APMError> {
-    let mut f = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .open(path)
-        .map_err(|e| APMErrorType::FileOpenError.into_apm_error(e.to_string()))?;
+// Where the checksum of an extracted package should be written, checked before anything is
+// extracted. Returns `None` when an existing file is to be skipped, symlinks are never written
+// through or replaced.
+fn checksum_output(path: &Path, overwrite: OverwritePolicy) -> Result<Option<&Path>, APMError> {
+    let exists = |message: &str| {
+        return Err(APMErrorType::FileExistsError
+            .into_apm_error(format!("{} {}", message, path.display()))
+            .with_path(path));
+    };
 
-    let mut contents = Vec::new();
+    return match fs::symlink_metadata(path) {
+        Err(_) => Ok(Some(path)),
+        Ok(m) if m.file_type().is_symlink() => {
+            exists("Refusing to write the checksum through the symlink at")
+        }
+        Ok(_) => match overwrite {
+            OverwritePolicy::Error => exists("There already exists a file at"),
+            OverwritePolicy::Skip => Ok(None),
+            OverwritePolicy::Overwrite => Ok(Some(path)),
+        },
+    };
+}
 
-    f.read_to_end(&mut contents)
-        .map_err(|e| APMErrorType::FileReadError.into_apm_error(e.to_string()))?;
+// Turns an entry name into a relative path, refusing anything which could point outside of the
+// extraction directory.
+fn entry_output_path(name: &str) -> Result<PathBuf, APMError> {
+    let unsafe_name = || {
+        return APMErrorType::UnsafeEntryPathError.into_apm_error(format!(
+            "The entry {} would be extracted outside of the output directory",
+            name
+        ));
+    };
 
-    return dump_file_names_zip_bytes(&contents);
+    if name.starts_with('/') || name.starts_with('\\') || name.contains('\0') {
+        return Err(unsafe_name());
+    }
+
+    let mut path = PathBuf::new();
+
+    for part in name.split(['/', '\\']) {
+        if part.is_empty() || part == "." {
+            continue;
+        }
+
+        let mut components = Path::new(part).components();
+
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(c)), None) => path.push(c),
+            _ => return Err(unsafe_name()),
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err(unsafe_name());
+    }
+
+    return Ok(path);
 }
 
-pub fn dump_file_names_zip_bytes(zip_bytes: &[u8]) -> Result<Vec<String>, APMError> {
-    let zip_archive = ZipArchive::new(Cursor::new(zip_bytes))
-        .map_err(|e| APMErrorType::ZIPArchiveOpenError.into_apm_error(e.to_string()))?;
+// Creates `dir` one level at a time, checking that each part which already exists resolves to
+// somewhere inside `root` before anything is created in it, so a symlink beneath `root` cannot be
+// used to create directories elsewhere.
+fn create_dir_inside(root: &Path, dir: &Path) -> Result<(), APMError> {
+    let outside = |resolved: &Path| {
+        return APMErrorType::UnsafeEntryPathError.into_apm_error(format!(
+            "{} resolves to {}, which is outside of {}",
+            dir.display(),
+            resolved.display(),
+            root.display()
+        ));
+    };
+
+    let relative = dir.strip_prefix(root).map_err(|_| outside(dir))?;
+    let mut current = root.to_path_buf();
+
+    for component in relative.components() {
+        current.push(component);
+
+        if fs::symlink_metadata(&current).is_err() {
+            fs::create_dir(&current).map_err(|e| {
+                APMErrorType::DirectoryCreationError
+                    .from_error(e)
+                    .with_path(&current)
+            })?;
+
+            continue;
+        }
+
+        let resolved = current.canonicalize().map_err(|e| {
+            APMErrorType::FileOpenError
+                .from_error(e)
+                .with_path(&current)
+        })?;
+
+        if !resolved.starts_with(root) {
+            return Err(outside(&resolved));
+        }
+    }
 
-    return Ok(zip_archive.file_names().map(|s| s.to_string()).collect());
+    return Ok(());
 }
 
-pub fn dump_archer_hash_zip_file(zip_bytes: &[u8]) -> Result<String, APMError> {
-    let mut zip_archive = ZipArchive::new(Cursor::new(zip_bytes))
-        .map_err(|e| APMErrorType::ZIPArchiveOpenError.into_apm_error(e.to_string()))?;
-    let mut zip_file = zip_archive
-        .by_name(HIDDEN_FILE_PATH)
-        .map_err(|e| APMErrorType::ZIPArchiveHiddenNotFoundError.into_apm_error(e.to_string()))?;
+/// Writes `dest` through a temporary file in the same directory which is synced and then renamed
+/// over it, so `dest` is always either the old file or the complete new one. With `backup` an
+/// existing file is first copied to `<dest>.bak`, whose path is returned.
+pub fn write_atomically<F>(dest: &Path, backup: bool, write: F) -> Result<Option<PathBuf>, APMError>
+where
+    F: FnOnce(&mut File) -> Result<(), APMError>,
+{
+    let dir = match dest.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let file_name = dest.file_name().ok_or_else(|| {
+        APMErrorType::FileOpenError
+            .into_apm_error(format!("{} is not a file path", dest.display()))
+            .with_path(dest)
+    })?;
+
+    let mut temp = tempfile::Builder::new()
+        .prefix(&format!(".{}.", file_name.to_string_lossy()))
+        .suffix(".tmp")
+        .tempfile_in(dir)
+        .map_err(|e| {
+            APMErrorType::TempFileCreationError
+                .from_error(e)
+                .with_path(dir)
+        })?;
+    let existing = fs::metadata(dest).ok();
 
-    let mut hash = Vec::new();
+    write(temp.as_file_mut())?;
 
-    zip_file
-        .read_to_end(&mut hash)
-        .map_err(|e| APMErrorType::ZIPArchiveReadError.into_apm_error(e.to_string()))?;
+    // Temporary files are only readable by their owner, keep the permissions of the file being
+    // replaced instead.
+    let permissions = match &existing {
+        Some(metadata) => Some(metadata.permissions()),
+        None => default_permissions(),
+    };
+
+    if let Some(permissions) = permissions {
+        temp.as_file().set_permissions(permissions).map_err(|e| {
+            APMErrorType::FileWriteError
+                .from_error(e)
+                .with_path(temp.path())
+        })?;
+    }
+
+    temp.as_file().sync_all().map_err(|e| {
+        APMErrorType::FileWriteError
+            .from_error(e)
+            .with_path(temp.path())
+    })?;
+
+    let mut backup_path = None;
+
+    if backup && existing.is_some() {
+        let mut p = dest.as_os_str().to_owned();
+        p.push(".");
+        p.push(BACKUP_EXTENSION);
+
+        let p = PathBuf::from(p);
+
+        fs::copy(dest, &p)
+            .and_then(|_| File::open(&p))
+            .and_then(|f| f.sync_all())
+            .map_err(|e| APMErrorType::FileWriteError.from_error(e).with_path(&p))?;
+
+        backup_path = Some(p);
+    }
 
-    return String::from_utf8(hash)
-        .map_err(|e| APMErrorType::HashUTF8Error.into_apm_error(e.to_string()));
+    temp.persist(dest).map_err(|e| {
+        APMErrorType::FileWriteError
+            .from_error(e.error)
+            .with_path(dest)
+    })?;
+
+    // Make the rename itself durable, not every platform allows directories to be synced.
+    #[cfg(unix)]
+    if let Ok(d) = File::open(dir) {
+        let _ = d.sync_all();
+    }
+
+    return Ok(backup_path);
+}
+
+#[cfg(unix)]
+fn default_permissions() -> Option<fs::Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+
+    return Some(fs::Permissions::from_mode(0o644));
+}
+
+#[cfg(not(unix))]
+fn default_permissions() -> Option<fs::Permissions> {
+    return None;
 }
 
-fn generate_archer_hash_from_bytes(bytes: &[u8]) -> [u8; 32] {
+trait ReadWriteSeek: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> ReadWriteSeek for T {}
+
+fn new_temp_file() -> Result<NamedTempFile, APMError> {
+    return NamedTempFile::new().map_err(|e| APMErrorType::TempFileCreationError.from_error(e));
+}
+
+fn open_file(path: &Path) -> Result<File, APMError> {
+    return OpenOptions::new()
+        .read(true)
+        .write(false)
+        .open(path)
+        .map_err(|e| APMErrorType::FileOpenError.from_error(e).with_path(path));
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    return match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    };
+}
+
+fn format_zip_datetime(dt: &DateTime) -> String {
+    return format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        dt.year(),
+        dt.month(),
+        dt.day(),
+        dt.hour(),
+        dt.minute(),
+        dt.second()
+    );
+}
+
+/// Calculates the SHA-256 digest of everything read from `reader` without buffering it all.
+pub fn hash_reader<R: Read>(reader: &mut R) -> Result<[u8; 32], APMError> {
     let mut hasher = Sha256::new();
 
-    hasher.update(bytes);
+    copy(reader, &mut hasher).map_err(|e| APMErrorType::FileReadError.from_error(e))?;
+
+    return Ok(hasher.finalize().into());
+}
+
+/// Hashes everything written through it with SHA-256.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        return Self {
+            inner,
+            hasher: Sha256::new(),
+        };
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        return self.hasher.finalize().into();
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+
+        self.hasher.update(&buf[..n]);
+
+        return Ok(n);
+    }
 
-    return hasher.finalize().into();
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.inner.flush();
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    const SAMPLE_ZIP: &'static str = "test_files/no_hidden.zip";
-    const SAMPLE_ZIP_HASH: &'static str = "BHp9Y1ONQRDhB6HN8mEgfktQnrigycbH+dQ3vNCFQew=";
+    const SAMPLE_ZIP: &str = "test_files/no_hidden.zip";
+    const SAMPLE_HIDDEN_ZIP: &str = "test_files/hidden.zip";
+    const SAMPLE_ZIP_HASH: &str = "BHp9Y1ONQRDhB6HN8mEgfktQnrigycbH+dQ3vNCFQew=";
+    // Made with Info-ZIP rather than the zip crate, whose central directory is laid out differently.
+    const SAMPLE_INFO_ZIP: &str = "test_files/info.zip";
 
     #[test]
     fn test_modify_sample() {
-        let (modified_zip, _) = insert_checksum_zip(SAMPLE_ZIP, false).unwrap();
-        let files = dump_file_names_zip_bytes(&modified_zip).unwrap();
+        let mut package = Package::open(SAMPLE_ZIP).unwrap();
+        package.add_checksum().unwrap();
+
+        let mut bytes = Vec::new();
+        package.write_to(&mut bytes).unwrap();
+
+        let mut modified = Package::from_bytes(bytes);
         let mut found = false;
 
-        for file in files {
-            found = found || &file == "/hidden";
+        for file in modified.contents().unwrap().file_names() {
+            found = found || file == "/hidden";
         }
 
         assert!(found);
-        let hash = dump_archer_hash_zip_file(&modified_zip).unwrap();
-        assert_eq!(hash, SAMPLE_ZIP_HASH);
+        let hash = modified.stored_checksum().unwrap();
+        assert_eq!(hash, Some(SAMPLE_ZIP_HASH));
+    }
+
+    #[test]
+    fn test_read_sample() {
+        let mut package = Package::open(SAMPLE_HIDDEN_ZIP).unwrap();
+        let contents = package.contents().unwrap();
+
+        assert_eq!(contents.len(), 6);
+        assert!(contents.has_checksum_entry());
+        assert_eq!(contents.stored_checksum(), Some(SAMPLE_ZIP_HASH));
+        assert_eq!(contents.uncompressed_size(), 84 + 44);
+        assert!(!package.is_modified());
+
+        assert!(package.remove_checksum().unwrap());
+        assert!(package.is_modified());
+        assert!(!package.contents().unwrap().has_checksum_entry());
+        assert_eq!(package.contents().unwrap().len(), 5);
+        assert!(!package.remove_checksum().unwrap());
+    }
+
+    #[test]
+    fn test_file_backed_modifications() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut package, _) =
+            Package::from_directory("test_files/test_dir", &CompressOptions::default()).unwrap();
+        let checksum = package.add_checksum().unwrap();
+
+        let out = dir.path().join("package.zip").display().to_string();
+        package.save(&out).unwrap();
+
+        let mut saved = Package::open(&out).unwrap();
+        assert_eq!(saved.verify().unwrap(), ChecksumStatus::Valid(checksum));
+        assert!(saved.remove_checksum().unwrap());
+        assert_eq!(saved.contents().unwrap().len(), 4);
+        assert_eq!(
+            Package::open(&out).unwrap().contents().unwrap().len(),
+            5,
+            "The source file must not be modified until the package is saved"
+        );
+    }
+
+    #[test]
+    fn test_save_in_place_with_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("package.zip").display().to_string();
+        std::fs::copy(SAMPLE_HIDDEN_ZIP, &out).unwrap();
+
+        let mut package = Package::open(&out).unwrap();
+        assert!(package.remove_checksum().unwrap());
+
+        let backup = package.save_with_backup(&out, true).unwrap().unwrap();
+        assert_eq!(backup, dir.path().join("package.zip.bak"));
+        assert_eq!(
+            std::fs::read(&backup).unwrap(),
+            std::fs::read(SAMPLE_HIDDEN_ZIP).unwrap()
+        );
+        assert_eq!(
+            std::fs::read(&out).unwrap(),
+            std::fs::read(SAMPLE_ZIP).unwrap()
+        );
+
+        // Only the package and its backup remain, the temporary file was renamed into place
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_remove_appended_checksum() {
+        let mut package = Package::open(SAMPLE_HIDDEN_ZIP).unwrap();
+        assert!(package.remove_checksum().unwrap());
+
+        let mut stripped = Vec::new();
+        package.write_to(&mut stripped).unwrap();
+
+        assert_eq!(stripped, std::fs::read(SAMPLE_ZIP).unwrap());
+    }
+
+    #[test]
+    fn test_remove_checksum_foreign_archive() {
+        let original = std::fs::read(SAMPLE_INFO_ZIP).unwrap();
+        let mut package = Package::open(SAMPLE_INFO_ZIP).unwrap();
+        package.add_checksum().unwrap();
+        assert!(package.remove_checksum().unwrap());
+
+        let mut stripped = Vec::new();
+        package.write_to(&mut stripped).unwrap();
+
+        assert_eq!(stripped, original);
+    }
+
+    #[test]
+    fn test_replace_checksum() {
+        fn checksum_entries(package: &mut Package) -> usize {
+            return package
+                .entries()
+                .unwrap()
+                .iter()
+                .filter(|e| e.name == HIDDEN_FILE_PATH)
+                .count();
+        }
+
+        let mut package = Package::open(SAMPLE_HIDDEN_ZIP).unwrap();
+        let e = package.add_checksum().unwrap_err();
+        assert_eq!(e.error_type(), APMErrorType::ChecksumExistsError);
+        assert!(!package.is_modified());
+
+        assert_eq!(package.replace_checksum().unwrap(), SAMPLE_ZIP_HASH);
+        assert_eq!(checksum_entries(&mut package), 1);
+        assert_eq!(
+            package.verify().unwrap(),
+            ChecksumStatus::Valid(SAMPLE_ZIP_HASH.to_string())
+        );
+
+        let mut package = Package::open(SAMPLE_ZIP).unwrap();
+        assert_eq!(package.replace_checksum().unwrap(), SAMPLE_ZIP_HASH);
+        assert_eq!(package.replace_checksum().unwrap(), SAMPLE_ZIP_HASH);
+        assert_eq!(checksum_entries(&mut package), 1);
+
+        // A checksum which is not the last entry is replaced by rewriting the archive.
+        let mut package = build_zip(&[(HIDDEN_FILE_PATH, b"stale"), ("file.txt", b"contents")]);
+        let checksum = package.replace_checksum().unwrap();
+        assert_eq!(checksum_entries(&mut package), 1);
+        assert_eq!(package.verify().unwrap(), ChecksumStatus::Valid(checksum));
+    }
+
+    #[test]
+    fn test_recompress() {
+        let mut package = Package::open(SAMPLE_HIDDEN_ZIP).unwrap();
+        let before = package.entries().unwrap().to_vec();
+        let settings = CompressionSettings {
+            method: Some(CompressionMethod::Stored),
+            ..Default::default()
+        };
+
+        assert!(package.recompress(&settings).unwrap());
+        assert!(!package.contents().unwrap().has_checksum_entry());
+
+        let after = package.entries().unwrap().to_vec();
+        assert_eq!(after.len(), before.len() - 1);
+
+        for (entry, before) in after.iter().zip(before.iter()) {
+            assert_eq!(entry.name, before.name);
+            assert_eq!(entry.crc32, before.crc32);
+            assert_eq!(entry.compression, CompressionMethod::Stored);
+        }
+    }
+
+    #[test]
+    fn test_remove_checksum_raw_copy() {
+        let mut bytes = Vec::new();
+        {
+            let mut writer = ZipWriter::new(Cursor::new(&mut bytes));
+            let stored = FileOptions::default()
+                .compression_method(CompressionMethod::Stored)
+                .last_modified_time(DateTime::from_date_and_time(2001, 2, 3, 4, 5, 6).unwrap())
+                .unix_permissions(0o600);
+
+            writer.start_file("stored.txt", stored).unwrap();
+            writer.write_all(b"stored contents").unwrap();
+            writer
+                .start_file(HIDDEN_FILE_PATH, FileOptions::default())
+                .unwrap();
+            writer.write_all(b"not the last entry").unwrap();
+            writer
+                .start_file("deflated.txt", FileOptions::default())
+                .unwrap();
+            writer.write_all(&[b'a'; 1024]).unwrap();
+            writer.finish().unwrap();
+        }
+
+        let original_entries = Package::from_bytes(bytes.clone())
+            .entries()
+            .unwrap()
+            .to_vec();
+
+        let mut package = Package::from_bytes(bytes);
+        assert!(package.remove_checksum().unwrap());
+
+        let entries = package.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+
+        for entry in entries {
+            let before = original_entries
+                .iter()
+                .find(|e| e.name == entry.name)
+                .unwrap();
+
+            assert_eq!(entry.compression, before.compression);
+            assert_eq!(entry.compressed_size, before.compressed_size);
+            assert_eq!(entry.crc32, before.crc32);
+            // The zip crate only carries the permission bits across a raw copy.
+            assert_eq!(
+                entry.unix_mode.map(|m| m & 0o777),
+                before.unix_mode.map(|m| m & 0o777)
+            );
+            assert_eq!(
+                entry.last_modified.timepart(),
+                before.last_modified.timepart()
+            );
+            assert_eq!(
+                entry.last_modified.datepart(),
+                before.last_modified.datepart()
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_round_trip() {
+        let options = CompressOptions {
+            reproducible: true,
+            ..Default::default()
+        };
+        let (mut package, _) = Package::from_directory("test_files/test_dir", &options).unwrap();
+        let checksum = package.add_checksum().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let extract_options = ExtractOptions {
+            checksum_path: Some(dir.path().join("checksum")),
+            ..Default::default()
+        };
+        let summary = package
+            .extract(out.to_str().unwrap(), &extract_options)
+            .unwrap();
+
+        assert_eq!(summary.extracted.len(), 4);
+        assert_eq!(summary.checksum.as_deref(), Some(checksum.as_str()));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("checksum")).unwrap(),
+            checksum
+        );
+
+        // Compressing the extracted tree again gives back the same package.
+        let (mut repacked, _) = Package::from_directory(out.to_str().unwrap(), &options).unwrap();
+        assert_eq!(repacked.add_checksum().unwrap(), checksum);
+
+        // Existing files are an error unless another policy is chosen.
+        assert!(package
+            .extract(out.to_str().unwrap(), &extract_options)
+            .is_err());
+
+        let summary = package
+            .extract(
+                out.to_str().unwrap(),
+                &ExtractOptions {
+                    overwrite: OverwritePolicy::Skip,
+                    checksum_path: None,
+                },
+            )
+            .unwrap();
+        assert_eq!(summary.skipped.len(), 3);
+    }
+
+    #[test]
+    fn test_extract_rejects_traversal() {
+        let mut bytes = Vec::new();
+        {
+            let mut writer = ZipWriter::new(Cursor::new(&mut bytes));
+            writer
+                .start_file("safe.txt", FileOptions::default())
+                .unwrap();
+            writer.write_all(b"safe").unwrap();
+            writer
+                .start_file("../escaped.txt", FileOptions::default())
+                .unwrap();
+            writer.write_all(b"escaped").unwrap();
+            writer.finish().unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let result = Package::from_bytes(bytes).extract(out.to_str().unwrap(), &Default::default());
+
+        assert!(result.is_err());
+        assert!(!out.join("safe.txt").exists());
+        assert!(!dir.path().join("escaped.txt").exists());
+
+        assert!(entry_output_path("/etc/passwd").is_err());
+        assert!(entry_output_path("a/../../b").is_err());
+        assert_eq!(
+            entry_output_path("a/./b/").unwrap(),
+            Path::new("a").join("b")
+        );
+    }
+
+    #[test]
+    fn test_extract_checksum_conflicts() {
+        let package = Package::open(SAMPLE_HIDDEN_ZIP).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let checksum_path = dir.path().join("checksum");
+        fs::write(&checksum_path, "existing").unwrap();
+
+        // The existing checksum file is found before anything is extracted.
+        let extract_options = ExtractOptions {
+            checksum_path: Some(checksum_path.clone()),
+            ..Default::default()
+        };
+        assert!(package
+            .extract(out.to_str().unwrap(), &extract_options)
+            .is_err());
+        assert!(!out.exists());
+
+        #[cfg(unix)]
+        {
+            let target = dir.path().join("target");
+            let link = dir.path().join("link");
+            std::os::unix::fs::symlink(&target, &link).unwrap();
+
+            let extract_options = ExtractOptions {
+                overwrite: OverwritePolicy::Overwrite,
+                checksum_path: Some(link),
+            };
+            assert!(package
+                .extract(out.to_str().unwrap(), &extract_options)
+                .is_err());
+            assert!(!target.exists());
+            assert!(!out.exists());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_rejects_symlinked_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let outside = dir.path().join("outside");
+        fs::create_dir(&out).unwrap();
+        fs::create_dir(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, out.join("link")).unwrap();
+
+        let result = build_zip(&[("link/new/file.txt", b"escaped")])
+            .extract(out.to_str().unwrap(), &Default::default());
+
+        assert_eq!(
+            result.unwrap_err().error_type(),
+            APMErrorType::UnsafeEntryPathError
+        );
+        assert!(!outside.join("new").exists());
+    }
+
+    fn build_zip(files: &[(&str, &[u8])]) -> Package {
+        let mut bytes = Vec::new();
+        {
+            let mut writer = ZipWriter::new(Cursor::new(&mut bytes));
+
+            for (name, contents) in files {
+                writer.start_file(*name, FileOptions::default()).unwrap();
+                writer.write_all(contents).unwrap();
+            }
+
+            writer.finish().unwrap();
+        }
+
+        return Package::from_bytes(bytes);
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut old = build_zip(&[
+            ("config.xml", b"<a>\n  <b>1</b>\n</a>\n"),
+            ("same.txt", b"unchanged"),
+            ("removed.bin", &[0, 1, 2]),
+        ]);
+        let mut new = build_zip(&[
+            ("config.xml", b"<a>\n  <b>22</b>\n</a>\n"),
+            ("same.txt", b"unchanged"),
+            ("added.txt", b"new"),
+        ]);
+
+        let result = diff(&mut old, &mut new, &DiffOptions::default()).unwrap();
+        let summary: Vec<(&str, ChangeKind, i64)> = result
+            .changes
+            .iter()
+            .map(|c| (c.name.as_str(), c.kind, c.size_delta))
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                ("added.txt", ChangeKind::Added, 3),
+                ("config.xml", ChangeKind::Modified, 1),
+                ("removed.bin", ChangeKind::Removed, -3),
+            ]
+        );
+        assert_eq!(
+            result.changes[1].text_diff.as_deref(),
+            Some("--- a/config.xml\n+++ b/config.xml\n@@ -1,3 +1,3 @@\n <a>\n-  <b>1</b>\n+  <b>22</b>\n </a>\n")
+        );
+
+        // Only the checksum differs between the samples.
+        let mut hidden = Package::open(SAMPLE_HIDDEN_ZIP).unwrap();
+        let mut no_hidden = Package::open(SAMPLE_ZIP).unwrap();
+        assert!(diff(&mut hidden, &mut no_hidden, &DiffOptions::default())
+            .unwrap()
+            .is_empty());
+
+        let options = DiffOptions {
+            include_checksum: true,
+            ..Default::default()
+        };
+        let result = diff(&mut hidden, &mut no_hidden, &options).unwrap();
+        assert_eq!(result.changes.len(), 1);
+        assert_eq!(result.changes[0].name, HIDDEN_FILE_PATH);
+        assert_eq!(result.changes[0].kind, ChangeKind::Removed);
+    }
+
+    #[test]
+    fn test_info() {
+        let info = Package::open(SAMPLE_HIDDEN_ZIP).unwrap().info().unwrap();
+
+        assert_eq!(info.entry_count, 6);
+        assert_eq!(info.file_size, 1183);
+        assert!(info.checksum_valid);
+        assert_eq!(info.stored_checksum.as_deref(), Some(SAMPLE_ZIP_HASH));
+        assert_eq!(info.compression_methods.get("Deflated"), Some(&4));
+        assert_eq!(info.entries[1].name, "test_dir/out.txt");
+        assert_eq!(info.entries[1].last_modified, "2022-04-22 23:01:52");
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut package = Package::open(SAMPLE_HIDDEN_ZIP).unwrap();
+        assert_eq!(
+            package.verify().unwrap(),
+            ChecksumStatus::Valid(SAMPLE_ZIP_HASH.to_string())
+        );
+
+        let mut package = Package::open(SAMPLE_ZIP).unwrap();
+        assert_eq!(
+            package.verify().unwrap(),
+            ChecksumStatus::Missing {
+                computed: SAMPLE_ZIP_HASH.to_string()
+            }
+        );
+
+        // Editing a package after checksumming it must be detected.
+        let mut package = Package::open(SAMPLE_ZIP).unwrap();
+        package.add_checksum().unwrap();
+        let mut bytes = Vec::new();
+        package.write_to(&mut bytes).unwrap();
+        {
+            let mut writer = ZipWriter::new_append(Cursor::new(&mut bytes)).unwrap();
+            writer
+                .start_file("extra.txt", FileOptions::default())
+                .unwrap();
+            writer.write_all(b"edited").unwrap();
+            writer.finish().unwrap();
+        }
+
+        match Package::from_bytes(bytes).verify().unwrap() {
+            ChecksumStatus::Mismatch { stored, .. } => assert_eq!(stored, SAMPLE_ZIP_HASH),
+            status => panic!("Unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn test_verify_foreign_archive() {
+        let original = std::fs::read(SAMPLE_INFO_ZIP).unwrap();
+        let expected = base64::encode(Sha256::digest(&original));
+
+        for mut package in [
+            Package::open(SAMPLE_INFO_ZIP).unwrap(),
+            Package::from_bytes(original.clone()),
+        ] {
+            assert_eq!(package.add_checksum().unwrap(), expected);
+            assert_eq!(
+                package.verify().unwrap(),
+                ChecksumStatus::Valid(expected.clone())
+            );
+        }
     }
 }
@@ -0,0 +1,1773 @@
+use crate::blobs::{to_hex, BlobStore, Manifest, MANIFEST_FORMAT_VERSION};
+use crate::error::{APMError, APMErrorType};
+use crate::packages::{write_atomically, ChecksumStatus, HashingWriter, Package};
+
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{copy, sink, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+const INDEX_FILE_NAME: &str = "index.json";
+const PACKAGES_DIR_NAME: &str = "packages";
+const INDEX_FORMAT_VERSION: u32 = 3;
+const METADATA_FORMAT_VERSION: u32 = 1;
+const MANIFEST_SUFFIX: &str = "manifest.json";
+const QUARANTINE_DIR_NAME: &str = "quarantine";
+const STORE_ENV_VAR: &str = "APM_STORE";
+const DEFAULT_STORE_DIR_NAME: &str = ".apm";
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Revision {
+    pub revision: u32,
+    pub version: Option<Version>,
+    /// A version recorded by an older apm which could not be read as a semantic version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_label: Option<String>,
+    pub file: String,
+    pub checksum: Option<String>,
+    pub size: u64,
+    pub added: u64,
+    /// How the package is kept, revisions stored before blob storage are whole zip files.
+    #[serde(default)]
+    pub storage: Storage,
+    /// A copy of the details in the revision's metadata so the store can be searched without
+    /// reading every metadata file.
+    #[serde(default)]
+    pub details: PackageDetails,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// `file` is the package itself.
+    #[default]
+    Zip,
+    /// `file` is a manifest for rebuilding the package from the blob directory.
+    Blobs,
+}
+
+/// The result of `PackageStore::export`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExportSummary {
+    pub backup: Option<PathBuf>,
+    /// Whether the exported package has exactly the bytes which were stored. Packages rebuilt from
+    /// blobs may not, although their contents are the same.
+    pub identical: bool,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct GcOptions {
+    /// Only report what would be removed.
+    pub dry_run: bool,
+    /// Files changed more recently than this are kept, as they may belong to a package which is
+    /// still being added.
+    pub min_age: Duration,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug, Default)]
+pub struct GcSummary {
+    pub removed_blobs: Vec<String>,
+    pub removed_temp_files: Vec<PathBuf>,
+    pub freed_bytes: u64,
+}
+
+/// Something wrong with a stored revision found by `PackageStore::fsck`.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    MissingFile {
+        path: PathBuf,
+    },
+    Unreadable {
+        error: String,
+    },
+    SizeMismatch {
+        recorded: u64,
+        actual: u64,
+    },
+    /// The `/hidden` entry does not match the package's contents.
+    ChecksumMismatch {
+        stored: String,
+        computed: String,
+    },
+    /// The `/hidden` entry is not the checksum recorded when the package was added.
+    RecordedChecksumMismatch {
+        recorded: Option<String>,
+        stored: Option<String>,
+    },
+    MissingBlob {
+        blob: String,
+        entry: String,
+    },
+    /// The blob's contents no longer have the hash it is named after.
+    CorruptBlob {
+        blob: String,
+        entry: String,
+    },
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct RevisionProblem {
+    pub name: String,
+    pub revision: u32,
+    pub problem: Problem,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug, Default)]
+pub struct FsckSummary {
+    pub revisions: usize,
+    pub blobs: usize,
+    pub problems: Vec<RevisionProblem>,
+    /// The files moved out of the store, in the quarantine directory.
+    pub quarantined: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct PackageRecord {
+    pub revisions: Vec<Revision>,
+    /// The revision marked as the latest, the newest revision when not set.
+    #[serde(default)]
+    pub latest: Option<u32>,
+}
+
+/// The metadata of a revision, kept as JSON next to its package in
+/// `packages/<name>/<revision>.json`. The format is described in the README.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Metadata {
+    pub format_version: u32,
+    pub name: String,
+    pub version: Option<Version>,
+    pub revision: u32,
+    pub created: u64,
+    pub checksum: Option<String>,
+    pub size: u64,
+    #[serde(flatten)]
+    pub details: PackageDetails,
+}
+
+/// The parts of a package's metadata which are supplied by the user rather than taken from the
+/// package itself.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct PackageDetails {
+    pub description: Option<String>,
+    pub author: Option<String>,
+    /// The version of Archer the package is meant to be installed on.
+    pub archer_version: Option<String>,
+    pub source_directory: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Selects revisions in `PackageStore::search`, every filter which is set must match.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct SearchQuery {
+    /// Matches names containing this, ignoring case.
+    pub name: Option<String>,
+    /// Matches revisions with all of these tags.
+    pub tags: Vec<String>,
+    pub version: Option<VersionSpec>,
+    /// Matches revisions added at or after this many seconds since the Unix epoch.
+    pub created_since: Option<u64>,
+    /// Matches revisions added at or before this many seconds since the Unix epoch.
+    pub created_until: Option<u64>,
+    /// Matches this Archer version and the versions within it, e.g. `6.9` matches `6.9.1`.
+    pub archer_version: Option<String>,
+    pub checksum: Option<String>,
+    /// Returns every matching revision instead of one per package.
+    pub all_revisions: bool,
+    pub order: SearchOrder,
+    pub reverse: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum SearchOrder {
+    #[default]
+    Name,
+    Version,
+    Created,
+    Size,
+}
+
+/// Selects versions of a package, either exactly (`1.2` meaning `1.2.0`) or with a requirement
+/// such as `^1.2` or `>=1.0, <2`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum VersionSpec {
+    Exact(Version),
+    Requirement(VersionReq),
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+struct StoreIndex {
+    format_version: u32,
+    packages: BTreeMap<String, PackageRecord>,
+}
+
+// Format 1 stored versions as free-form strings and had no latest pointer.
+#[derive(Deserialize)]
+struct StoreIndexV1 {
+    packages: BTreeMap<String, PackageRecordV1>,
+}
+
+#[derive(Deserialize)]
+struct PackageRecordV1 {
+    revisions: Vec<RevisionV1>,
+}
+
+#[derive(Deserialize)]
+struct RevisionV1 {
+    revision: u32,
+    version: Option<String>,
+    file: String,
+    checksum: Option<String>,
+    size: u64,
+    added: u64,
+}
+
+#[derive(Deserialize)]
+struct IndexHeader {
+    format_version: u32,
+}
+
+pub struct PackageStore {
+    root: PathBuf,
+    index: StoreIndex,
+}
+
+impl Default for StoreIndex {
+    fn default() -> Self {
+        return Self {
+            format_version: INDEX_FORMAT_VERSION,
+            packages: BTreeMap::new(),
+        };
+    }
+}
+
+impl StoreIndexV1 {
+    fn migrate(self) -> StoreIndex {
+        let packages = self
+            .packages
+            .into_iter()
+            .map(|(name, record)| {
+                let revisions = record
+                    .revisions
+                    .into_iter()
+                    .map(|r| {
+                        let version = r.version.as_deref().and_then(|v| parse_version(v).ok());
+                        let version_label = match version {
+                            Some(_) => None,
+                            None => r.version,
+                        };
+
+                        return Revision {
+                            revision: r.revision,
+                            version,
+                            version_label,
+                            file: r.file,
+                            checksum: r.checksum,
+                            size: r.size,
+                            added: r.added,
+                            storage: Storage::Zip,
+                            details: PackageDetails::default(),
+                        };
+                    })
+                    .collect();
+
+                return (
+                    name,
+                    PackageRecord {
+                        revisions,
+                        latest: None,
+                    },
+                );
+            })
+            .collect();
+
+        return StoreIndex {
+            format_version: INDEX_FORMAT_VERSION,
+            packages,
+        };
+    }
+}
+
+impl Revision {
+    /// The version for display, `-` for unversioned revisions.
+    pub fn version_string(&self) -> String {
+        return match (&self.version, &self.version_label) {
+            (Some(v), _) => v.to_string(),
+            (None, Some(label)) => label.clone(),
+            (None, None) => "-".to_string(),
+        };
+    }
+
+    // Revisions are ordered by version, unversioned ones first, and then by when they were added.
+    fn order_key(&self) -> (Option<&Version>, u32) {
+        return (self.version.as_ref(), self.revision);
+    }
+}
+
+impl SearchQuery {
+    pub fn matches(&self, name: &str, revision: &Revision) -> bool {
+        if let Some(query) = &self.name {
+            if !name.to_lowercase().contains(&query.to_lowercase()) {
+                return false;
+            }
+        }
+
+        if !self.tags.iter().all(|t| revision.details.tags.contains(t)) {
+            return false;
+        }
+
+        if let Some(spec) = &self.version {
+            if !revision.version.as_ref().is_some_and(|v| spec.matches(v)) {
+                return false;
+            }
+        }
+
+        if self.created_since.is_some_and(|t| revision.added < t)
+            || self.created_until.is_some_and(|t| revision.added > t)
+        {
+            return false;
+        }
+
+        if let Some(query) = &self.archer_version {
+            let query = query.to_lowercase();
+            let matched = revision.details.archer_version.as_ref().is_some_and(|v| {
+                let v = v.to_lowercase();
+                return v == query || v.starts_with(&format!("{}.", query));
+            });
+
+            if !matched {
+                return false;
+            }
+        }
+
+        if let Some(checksum) = &self.checksum {
+            if revision.checksum.as_ref() != Some(checksum) {
+                return false;
+            }
+        }
+
+        return true;
+    }
+}
+
+impl PackageDetails {
+    /// Trims every field, dropping the empty ones, and sorts the tags. Tags cannot be empty or
+    /// contain whitespace or commas.
+    pub fn normalize(mut self) -> Result<Self, APMError> {
+        let trim = |s: Option<String>| {
+            return s.map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+        };
+
+        self.description = trim(self.description);
+        self.author = trim(self.author);
+        self.archer_version = trim(self.archer_version);
+        self.source_directory = trim(self.source_directory);
+
+        for tag in self.tags.iter_mut() {
+            *tag = tag.trim().to_string();
+
+            if tag.is_empty() || tag.contains(|c: char| c.is_whitespace() || c == ',') {
+                return Err(APMErrorType::InvalidMetadataError.into_apm_error(format!(
+                    "'{}' is not a valid tag, tags cannot be empty or contain spaces or commas",
+                    tag
+                )));
+            }
+        }
+
+        self.tags.sort();
+        self.tags.dedup();
+
+        return Ok(self);
+    }
+}
+
+impl PackageRecord {
+    /// The revision marked as the latest, or the newest one if none has been marked.
+    pub fn latest(&self) -> Option<&Revision> {
+        if let Some(latest) = self.latest {
+            if let Some(r) = self.revisions.iter().find(|r| r.revision == latest) {
+                return Some(r);
+            }
+        }
+
+        return self.newest();
+    }
+
+    /// The revision which was added last.
+    pub fn newest(&self) -> Option<&Revision> {
+        return self.revisions.iter().max_by_key(|r| r.revision);
+    }
+
+    /// The latest revision when `version` is not given, otherwise the highest matching version.
+    pub fn find(&self, version: Option<&VersionSpec>) -> Option<&Revision> {
+        let spec = match version {
+            Some(spec) => spec,
+            None => return self.latest(),
+        };
+
+        return self
+            .revisions
+            .iter()
+            .filter(|r| r.version.as_ref().is_some_and(|v| spec.matches(v)))
+            .max_by(|a, b| a.order_key().cmp(&b.order_key()));
+    }
+
+    /// Every revision ordered from the lowest version to the highest.
+    pub fn versions(&self) -> Vec<&Revision> {
+        let mut revisions: Vec<&Revision> = self.revisions.iter().collect();
+
+        revisions.sort_by(|a, b| a.order_key().cmp(&b.order_key()));
+
+        return revisions;
+    }
+
+    fn next_revision(&self) -> u32 {
+        return self.newest().map(|r| r.revision + 1).unwrap_or(1);
+    }
+}
+
+impl VersionSpec {
+    pub fn parse(spec: &str) -> Result<Self, APMError> {
+        let spec = spec.trim();
+        let is_requirement = spec.starts_with(['^', '~', '=', '>', '<', '*']) || spec.contains(',');
+
+        if !is_requirement {
+            return Ok(VersionSpec::Exact(parse_version(spec)?));
+        }
+
+        return VersionReq::parse(spec)
+            .map(VersionSpec::Requirement)
+            .map_err(|e| {
+                APMErrorType::InvalidVersionError
+                    .into_apm_error(format!(
+                        "'{}' is not a valid version requirement: {}",
+                        spec, e
+                    ))
+                    .with_source(e)
+            });
+    }
+
+    pub fn matches(&self, version: &Version) -> bool {
+        return match self {
+            VersionSpec::Exact(v) => v == version,
+            VersionSpec::Requirement(req) => req.matches(version),
+        };
+    }
+}
+
+impl PackageStore {
+    /// Resolves the store location from `APM_STORE`, falling back to `~/.apm`.
+    pub fn default_path() -> Result<PathBuf, APMError> {
+        if let Some(p) = env::var_os(STORE_ENV_VAR) {
+            return Ok(PathBuf::from(p));
+        }
+
+        let home = env::var_os("HOME")
+            .or_else(|| env::var_os("USERPROFILE"))
+            .ok_or_else(|| {
+                APMErrorType::StoreLocationError.into_apm_error(format!(
+                    "Could not determine the home directory, set {} to choose a store location",
+                    STORE_ENV_VAR
+                ))
+            })?;
+
+        return Ok(PathBuf::from(home).join(DEFAULT_STORE_DIR_NAME));
+    }
+
+    /// Opens the store at `root`. A missing store is empty, its directories are only created when
+    /// something is first written to it.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self, APMError> {
+        let root = root.as_ref().to_path_buf();
+        let index_path = root.join(INDEX_FILE_NAME);
+        let index = if index_path.exists() {
+            let mut contents = String::new();
+
+            OpenOptions::new()
+                .read(true)
+                .open(&index_path)
+                .and_then(|mut f| f.read_to_string(&mut contents))
+                .map_err(|e| {
+                    APMErrorType::StoreIndexReadError
+                        .from_error(e)
+                        .with_path(&index_path)
+                })?;
+
+            let read_error = |e: serde_json::Error| {
+                return APMErrorType::StoreIndexReadError
+                    .from_error(e)
+                    .with_path(&index_path);
+            };
+            let header = serde_json::from_str::<IndexHeader>(&contents).map_err(read_error)?;
+
+            if header.format_version > INDEX_FORMAT_VERSION {
+                return Err(APMErrorType::StoreIndexReadError
+                    .into_apm_error(format!(
+                        "The store index uses format version {}, this version of apm supports up to {}",
+                        header.format_version, INDEX_FORMAT_VERSION
+                    ))
+                    .with_path(&index_path));
+            }
+
+            // Older indexes are upgraded in memory and written in the new format on the next change.
+            // Format 3 only added blob storage, so format 2 can be read as it is.
+            match header.format_version {
+                1 => serde_json::from_str::<StoreIndexV1>(&contents)
+                    .map_err(read_error)?
+                    .migrate(),
+                _ => StoreIndex {
+                    format_version: INDEX_FORMAT_VERSION,
+                    ..serde_json::from_str::<StoreIndex>(&contents).map_err(read_error)?
+                },
+            }
+        } else {
+            StoreIndex::default()
+        };
+
+        return Ok(Self { root, index });
+    }
+
+    pub fn root(&self) -> &Path {
+        return &self.root;
+    }
+
+    pub fn packages(&self) -> impl Iterator<Item = (&str, &PackageRecord)> {
+        return self.index.packages.iter().map(|(k, v)| (k.as_str(), v));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PackageRecord> {
+        return self.index.packages.get(name);
+    }
+
+    /// Finds the latest revision of a package, or the highest version matching `version`, which
+    /// is parsed as a `VersionSpec`.
+    pub fn find(&self, name: &str, version: Option<&str>) -> Result<&Revision, APMError> {
+        let spec = version.map(VersionSpec::parse).transpose()?;
+
+        return self.get_record(name)?.find(spec.as_ref()).ok_or_else(|| {
+            APMErrorType::PackageNotFoundError.into_apm_error(format!(
+                "There is no version {} of the package {} in the store",
+                version.unwrap_or_default(),
+                name
+            ))
+        });
+    }
+
+    fn get_record(&self, name: &str) -> Result<&PackageRecord, APMError> {
+        return self.get(name).ok_or_else(|| {
+            APMErrorType::PackageNotFoundError
+                .into_apm_error(format!("There is no package named {} in the store", name))
+        });
+    }
+
+    fn get_record_mut(&mut self, name: &str) -> Result<&mut PackageRecord, APMError> {
+        return self.index.packages.get_mut(name).ok_or_else(|| {
+            APMErrorType::PackageNotFoundError
+                .into_apm_error(format!("There is no package named {} in the store", name))
+        });
+    }
+
+    /// Marks the revision `version` selects as the latest of `name`.
+    pub fn set_latest(&mut self, name: &str, version: &str) -> Result<&Revision, APMError> {
+        let revision = self.find(name, Some(version))?.revision;
+
+        self.get_record_mut(name)?.latest = Some(revision);
+        self.write_index()?;
+
+        return self.find(name, None);
+    }
+
+    /// Moves the latest pointer of `name` to the revision before it in version order.
+    pub fn rollback(&mut self, name: &str) -> Result<&Revision, APMError> {
+        let record = self.get_record(name)?;
+        let versions = record.versions();
+        let current = record.latest().map(|r| r.revision);
+        let position = versions.iter().position(|r| Some(r.revision) == current);
+
+        let previous = match position {
+            Some(i) if i > 0 => versions[i - 1].revision,
+            _ => {
+                return Err(APMErrorType::PackageNotFoundError.into_apm_error(format!(
+                    "There is no version of {} before the latest one",
+                    name
+                )))
+            }
+        };
+
+        self.get_record_mut(name)?.latest = Some(previous);
+        self.write_index()?;
+
+        return self.find(name, None);
+    }
+
+    /// Saves the package stored for `revision` to `dest`, keeping the file at `dest` as
+    /// `<dest>.bak` when `backup` is set.
+    pub fn export(
+        &self,
+        revision: &Revision,
+        dest: &str,
+        backup: bool,
+    ) -> Result<ExportSummary, APMError> {
+        let package = self.open_package(revision)?;
+        let identical = match revision.storage {
+            Storage::Zip => true,
+            Storage::Blobs => self.manifest(revision)?.is_identical(&package)?,
+        };
+
+        return Ok(ExportSummary {
+            backup: package.save_with_backup(dest, backup)?,
+            identical,
+        });
+    }
+
+    /// Opens the package stored for `revision`, rebuilding it from its blobs if necessary.
+    pub fn open_package(&self, revision: &Revision) -> Result<Package, APMError> {
+        return match revision.storage {
+            Storage::Zip => Package::open(&self.revision_path(revision).display().to_string()),
+            Storage::Blobs => self.manifest(revision)?.assemble(&self.blobs()),
+        };
+    }
+
+    /// Reads the manifest of a revision kept in the blob directory.
+    pub fn manifest(&self, revision: &Revision) -> Result<Manifest, APMError> {
+        let path = self.revision_path(revision);
+
+        if revision.storage != Storage::Blobs {
+            return Err(APMErrorType::ManifestReadError
+                .into_apm_error(format!(
+                    "Revision {} is stored as a zip file and has no manifest",
+                    revision.revision
+                ))
+                .with_path(&path));
+        }
+
+        let contents = fs::read(&path).map_err(|e| {
+            APMErrorType::ManifestReadError
+                .from_error(e)
+                .with_path(&path)
+        })?;
+        let header = serde_json::from_slice::<IndexHeader>(&contents).map_err(|e| {
+            APMErrorType::ManifestReadError
+                .from_error(e)
+                .with_path(&path)
+        })?;
+
+        if header.format_version > MANIFEST_FORMAT_VERSION {
+            return Err(APMErrorType::ManifestReadError
+                .into_apm_error(format!(
+                    "The manifest uses format version {}, this version of apm supports up to {}",
+                    header.format_version, MANIFEST_FORMAT_VERSION
+                ))
+                .with_path(&path));
+        }
+
+        return serde_json::from_slice::<Manifest>(&contents).map_err(|e| {
+            APMErrorType::ManifestReadError
+                .from_error(e)
+                .with_path(&path)
+        });
+    }
+
+    pub fn blobs(&self) -> BlobStore {
+        return BlobStore::new(&self.root);
+    }
+
+    /// The path of the file for `revision`, its package or its manifest depending on how it is
+    /// stored.
+    pub fn revision_path(&self, revision: &Revision) -> PathBuf {
+        return self.root.join(PACKAGES_DIR_NAME).join(&revision.file);
+    }
+
+    pub fn metadata_path(&self, revision: &Revision) -> PathBuf {
+        let path = self.revision_path(revision);
+
+        return path.with_file_name(format!("{}.json", revision.revision));
+    }
+
+    /// Reads the metadata of a revision of `name`. Revisions stored before metadata was recorded
+    /// get metadata made from the index.
+    pub fn metadata(&self, name: &str, revision: &Revision) -> Result<Metadata, APMError> {
+        let path = self.metadata_path(revision);
+
+        if !path.exists() {
+            return Ok(Metadata {
+                format_version: METADATA_FORMAT_VERSION,
+                name: name.to_string(),
+                version: revision.version.clone(),
+                revision: revision.revision,
+                created: revision.added,
+                checksum: revision.checksum.clone(),
+                size: revision.size,
+                details: revision.details.clone(),
+            });
+        }
+
+        let contents = fs::read(&path).map_err(|e| {
+            APMErrorType::MetadataReadError
+                .from_error(e)
+                .with_path(&path)
+        })?;
+        let header = serde_json::from_slice::<IndexHeader>(&contents).map_err(|e| {
+            APMErrorType::MetadataReadError
+                .from_error(e)
+                .with_path(&path)
+        })?;
+
+        if header.format_version > METADATA_FORMAT_VERSION {
+            return Err(APMErrorType::MetadataReadError
+                .into_apm_error(format!(
+                    "The metadata uses format version {}, this version of apm supports up to {}",
+                    header.format_version, METADATA_FORMAT_VERSION
+                ))
+                .with_path(&path));
+        }
+
+        return serde_json::from_slice::<Metadata>(&contents).map_err(|e| {
+            APMErrorType::MetadataReadError
+                .from_error(e)
+                .with_path(&path)
+        });
+    }
+
+    /// Replaces the details in the metadata of a revision of `name`.
+    pub fn set_details(
+        &mut self,
+        name: &str,
+        revision: &Revision,
+        details: PackageDetails,
+    ) -> Result<Metadata, APMError> {
+        let mut metadata = self.metadata(name, revision)?;
+
+        metadata.details = details.normalize()?;
+        self.write_metadata(revision, &metadata)?;
+
+        if let Some(r) = self
+            .get_record_mut(name)?
+            .revisions
+            .iter_mut()
+            .find(|r| r.revision == revision.revision)
+        {
+            r.details = metadata.details.clone();
+        }
+
+        self.write_index()?;
+
+        return Ok(metadata);
+    }
+
+    /// Finds the revisions matching `query` using the index. Unless every revision is asked for,
+    /// each package appears once, with its latest revision if it matches and otherwise its
+    /// highest matching version.
+    pub fn search(&self, query: &SearchQuery) -> Vec<(&str, &Revision)> {
+        let mut results = Vec::new();
+
+        for (name, record) in self.packages() {
+            let matching: Vec<&Revision> = record
+                .versions()
+                .into_iter()
+                .filter(|r| query.matches(name, r))
+                .collect();
+
+            if query.all_revisions {
+                results.extend(matching.into_iter().map(|r| (name, r)));
+                continue;
+            }
+
+            let latest = record.latest().map(|r| r.revision);
+            let best = matching
+                .iter()
+                .find(|r| Some(r.revision) == latest)
+                .or(matching.last());
+
+            if let Some(r) = best {
+                results.push((name, *r));
+            }
+        }
+
+        results.sort_by(|(a_name, a), (b_name, b)| {
+            let by_name = a_name.cmp(b_name).then(a.order_key().cmp(&b.order_key()));
+
+            return match query.order {
+                SearchOrder::Name => by_name,
+                SearchOrder::Version => a.version.cmp(&b.version).then(by_name),
+                SearchOrder::Created => a.added.cmp(&b.added).then(by_name),
+                SearchOrder::Size => a.size.cmp(&b.size).then(by_name),
+            };
+        });
+
+        if query.reverse {
+            results.reverse();
+        }
+
+        return results;
+    }
+
+    fn write_metadata(&self, revision: &Revision, metadata: &Metadata) -> Result<(), APMError> {
+        let path = self.metadata_path(revision);
+        let contents = serde_json::to_vec_pretty(metadata)
+            .map_err(|e| APMErrorType::MetadataWriteError.from_error(e))?;
+
+        write_atomically(&path, false, |f| {
+            return f.write_all(&contents).map_err(|e| {
+                APMErrorType::MetadataWriteError
+                    .from_error(e)
+                    .with_path(&path)
+            });
+        })?;
+
+        return Ok(());
+    }
+
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        version: Option<&str>,
+        path: &str,
+    ) -> Result<&Revision, APMError> {
+        return self.add_package(name, version, &mut Package::open(path)?);
+    }
+
+    pub fn add_package(
+        &mut self,
+        name: &str,
+        version: Option<&str>,
+        package: &mut Package,
+    ) -> Result<&Revision, APMError> {
+        return self.add_package_with_details(name, version, package, PackageDetails::default());
+    }
+
+    /// Adds a package as a new revision of `name`, recording `details` in its metadata.
+    pub fn add_package_with_details(
+        &mut self,
+        name: &str,
+        version: Option<&str>,
+        package: &mut Package,
+        details: PackageDetails,
+    ) -> Result<&Revision, APMError> {
+        validate_package_name(name)?;
+
+        let details = details.normalize()?;
+        let version = version.map(parse_version).transpose()?;
+        let revision_number = self.get(name).map(|r| r.next_revision()).unwrap_or(1);
+        let file = format!("{}/{}.{}", name, revision_number, MANIFEST_SUFFIX);
+        let revision = Revision {
+            revision: revision_number,
+            version,
+            version_label: None,
+            checksum: package.stored_checksum()?.map(|c| c.to_string()),
+            size: package.size()?,
+            added: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            file,
+            storage: Storage::Blobs,
+            details,
+        };
+
+        let dest = self.revision_path(&revision);
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                APMErrorType::StoreCreationError
+                    .from_error(e)
+                    .with_path(parent)
+            })?;
+        }
+
+        if dest.exists() {
+            return Err(APMErrorType::FileExistsError
+                .into_apm_error(format!(
+                    "The store already contains a file for revision {} of {}",
+                    revision_number, name
+                ))
+                .with_path(&dest));
+        }
+
+        // Blobs are written first so a manifest never refers to one which is missing.
+        let manifest = Manifest::store(&self.blobs(), package)?;
+        let contents = serde_json::to_vec_pretty(&manifest)
+            .map_err(|e| APMErrorType::ManifestWriteError.from_error(e))?;
+
+        write_atomically(&dest, false, |f| {
+            return f.write_all(&contents).map_err(|e| {
+                APMErrorType::ManifestWriteError
+                    .from_error(e)
+                    .with_path(&dest)
+            });
+        })?;
+
+        self.write_metadata(
+            &revision,
+            &Metadata {
+                format_version: METADATA_FORMAT_VERSION,
+                name: name.to_string(),
+                version: revision.version.clone(),
+                revision: revision.revision,
+                created: revision.added,
+                checksum: revision.checksum.clone(),
+                size: revision.size,
+                details: revision.details.clone(),
+            },
+        )?;
+
+        let record = self.index.packages.entry(name.to_string()).or_default();
+
+        record.revisions.push(revision);
+        record.latest = Some(revision_number);
+
+        self.write_index()?;
+
+        return self.find(name, None);
+    }
+
+    /// Removes every revision of `name` matching `version` (or all of them when no version is
+    /// given) and returns the revisions which were removed.
+    pub fn remove(&mut self, name: &str, version: Option<&str>) -> Result<Vec<Revision>, APMError> {
+        let spec = version.map(VersionSpec::parse).transpose()?;
+        let record = self.get_record_mut(name)?;
+
+        let (removed, kept): (Vec<Revision>, Vec<Revision>) =
+            record.revisions.drain(..).partition(|r| match &spec {
+                Some(spec) => r.version.as_ref().is_some_and(|v| spec.matches(v)),
+                None => true,
+            });
+
+        record.revisions = kept;
+
+        if removed.iter().any(|r| Some(r.revision) == record.latest) {
+            record.latest = None;
+        }
+
+        if removed.is_empty() {
+            return Err(APMErrorType::PackageNotFoundError.into_apm_error(format!(
+                "There is no version {} of the package {} in the store",
+                version.unwrap_or_default(),
+                name
+            )));
+        }
+
+        if record.revisions.is_empty() {
+            self.index.packages.remove(name);
+        }
+
+        self.write_index()?;
+
+        for revision in &removed {
+            for p in [self.revision_path(revision), self.metadata_path(revision)] {
+                if p.exists() {
+                    fs::remove_file(&p).map_err(|e| {
+                        APMErrorType::PackageRemoveError.from_error(e).with_path(&p)
+                    })?;
+                }
+            }
+        }
+
+        if self.get(name).is_none() {
+            // Only empty directories are removed, anything left behind is kept for inspection.
+            let _ = fs::remove_dir(self.root.join(PACKAGES_DIR_NAME).join(name));
+        }
+
+        return Ok(removed);
+    }
+
+    /// Removes blobs which no revision uses and temporary files left behind by interrupted
+    /// commands.
+    pub fn gc(&self, options: &GcOptions) -> Result<GcSummary, APMError> {
+        let mut referenced = HashSet::new();
+
+        // An unreadable manifest stops the collection, otherwise the blobs it uses would be lost.
+        for (_, record) in self.packages() {
+            for revision in &record.revisions {
+                if revision.storage == Storage::Blobs {
+                    referenced.extend(self.manifest(revision)?.blobs().map(|b| b.to_string()));
+                }
+            }
+        }
+
+        let blobs = self.blobs();
+        let now = SystemTime::now();
+        let mut summary = GcSummary::default();
+
+        if !self.root.exists() {
+            return Ok(summary);
+        }
+
+        for entry in WalkDir::new(&self.root) {
+            let entry = entry.map_err(|e| APMErrorType::WalkdirError.from_error(e))?;
+
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let is_temp = file_name.starts_with('.') && file_name.ends_with(".tmp");
+            let is_unused_blob = entry.path().starts_with(blobs.root())
+                && !is_temp
+                && !referenced.contains(&file_name);
+
+            if !is_temp && !is_unused_blob {
+                continue;
+            }
+
+            let metadata = entry
+                .metadata()
+                .map_err(|e| APMErrorType::WalkdirError.from_error(e))?;
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|m| now.duration_since(m).ok())
+                .unwrap_or_default();
+
+            if age < options.min_age {
+                continue;
+            }
+
+            if !options.dry_run {
+                fs::remove_file(entry.path()).map_err(|e| {
+                    APMErrorType::PackageRemoveError
+                        .from_error(e)
+                        .with_path(entry.path())
+                })?;
+            }
+
+            summary.freed_bytes += metadata.len();
+
+            match is_temp {
+                true => summary.removed_temp_files.push(entry.into_path()),
+                false => summary.removed_blobs.push(file_name),
+            }
+        }
+
+        if !options.dry_run {
+            // Only the directories which are now empty are removed.
+            let dirs = WalkDir::new(blobs.root())
+                .min_depth(1)
+                .contents_first(true)
+                .into_iter()
+                .flatten()
+                .filter(|e| e.file_type().is_dir());
+
+            for entry in dirs {
+                let _ = fs::remove_dir(entry.path());
+            }
+        }
+
+        return Ok(summary);
+    }
+
+    /// Checks every revision in the store. Zip files are checked against their `/hidden` entry
+    /// and the checksum recorded when they were added, blobs are hashed again. With `quarantine`
+    /// the files of broken revisions and corrupt blobs are moved to `quarantine/<time>` and the
+    /// revisions are removed from the index.
+    pub fn fsck(&mut self, quarantine: bool) -> Result<FsckSummary, APMError> {
+        let mut summary = FsckSummary::default();
+        let mut checked_blobs = HashMap::new();
+
+        for (name, record) in self.packages() {
+            for revision in &record.revisions {
+                summary.revisions += 1;
+
+                for problem in self.check_revision(revision, &mut checked_blobs) {
+                    summary.problems.push(RevisionProblem {
+                        name: name.to_string(),
+                        revision: revision.revision,
+                        problem,
+                    });
+                }
+            }
+        }
+
+        summary.blobs = checked_blobs.len();
+
+        if quarantine && !summary.problems.is_empty() {
+            summary.quarantined = self.quarantine(&summary.problems)?;
+        }
+
+        return Ok(summary);
+    }
+
+    fn check_revision(
+        &self,
+        revision: &Revision,
+        checked_blobs: &mut HashMap<String, bool>,
+    ) -> Vec<Problem> {
+        let path = self.revision_path(revision);
+        let unreadable = |e: APMError| {
+            return vec![Problem::Unreadable {
+                error: e.description().to_string(),
+            }];
+        };
+
+        if !path.is_file() {
+            return vec![Problem::MissingFile { path }];
+        }
+
+        if revision.storage == Storage::Zip {
+            let mut problems = Vec::new();
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or_default();
+
+            if size != revision.size {
+                problems.push(Problem::SizeMismatch {
+                    recorded: revision.size,
+                    actual: size,
+                });
+            }
+
+            let status =
+                match Package::open(&path.display().to_string()).and_then(|mut p| p.verify()) {
+                    Ok(status) => status,
+                    Err(e) => return unreadable(e),
+                };
+            let stored = match status {
+                ChecksumStatus::Valid(c) => Some(c),
+                ChecksumStatus::Missing { .. } => None,
+                ChecksumStatus::Mismatch { stored, computed } => {
+                    problems.push(Problem::ChecksumMismatch {
+                        stored: stored.clone(),
+                        computed,
+                    });
+                    Some(stored)
+                }
+            };
+
+            if stored != revision.checksum {
+                problems.push(Problem::RecordedChecksumMismatch {
+                    recorded: revision.checksum.clone(),
+                    stored,
+                });
+            }
+
+            return problems;
+        }
+
+        let manifest = match self.manifest(revision) {
+            Ok(m) => m,
+            Err(e) => return unreadable(e),
+        };
+        let blobs = self.blobs();
+        let mut problems = Vec::new();
+
+        for entry in &manifest.entries {
+            let blob = match &entry.blob {
+                Some(blob) => blob,
+                None => continue,
+            };
+
+            let path = match blobs.path(blob) {
+                Ok(path) => path,
+                Err(e) => return unreadable(e),
+            };
+
+            if !path.is_file() {
+                problems.push(Problem::MissingBlob {
+                    blob: blob.clone(),
+                    entry: entry.name.clone(),
+                });
+                continue;
+            }
+
+            let intact = *checked_blobs
+                .entry(blob.clone())
+                .or_insert_with(|| blob_is_intact(&blobs, blob, entry.size));
+
+            if !intact {
+                problems.push(Problem::CorruptBlob {
+                    blob: blob.clone(),
+                    entry: entry.name.clone(),
+                });
+            }
+        }
+
+        // A package with missing or corrupt blobs cannot be rebuilt to check it any further.
+        if !problems.is_empty() {
+            return problems;
+        }
+
+        if manifest.size != revision.size {
+            problems.push(Problem::SizeMismatch {
+                recorded: revision.size,
+                actual: manifest.size,
+            });
+        }
+
+        let mut rebuilt = match manifest.assemble(&blobs) {
+            Ok(p) => p,
+            Err(e) => return unreadable(e),
+        };
+        let (identical, status) = match manifest
+            .is_identical(&rebuilt)
+            .and_then(|identical| Ok((identical, rebuilt.verify()?)))
+        {
+            Ok(r) => r,
+            Err(e) => return unreadable(e),
+        };
+
+        if let ChecksumStatus::Mismatch { stored, computed } = &status {
+            problems.push(Problem::ChecksumMismatch {
+                stored: stored.clone(),
+                computed: computed.clone(),
+            });
+        }
+
+        // A package rebuilt byte for byte has the SHA-256 recorded in the manifest and the `/hidden`
+        // entry it was stored with. Otherwise the checksum is recalculated when it is rebuilt, so
+        // the manifest's copy of the original is compared instead, when it has one.
+        let stored = match (identical, &manifest.checksum, &manifest.checksum_entry) {
+            (true, _, _) => Some(match status {
+                ChecksumStatus::Valid(c) => Some(c),
+                ChecksumStatus::Mismatch { stored, .. } => Some(stored),
+                ChecksumStatus::Missing { .. } => None,
+            }),
+            (false, Some(checksum), _) => Some(Some(checksum.clone())),
+            (false, None, None) => Some(None),
+            (false, None, Some(_)) => None,
+        };
+
+        if let Some(stored) = stored {
+            if stored != revision.checksum {
+                problems.push(Problem::RecordedChecksumMismatch {
+                    recorded: revision.checksum.clone(),
+                    stored,
+                });
+            }
+        }
+
+        return problems;
+    }
+
+    fn quarantine(&mut self, problems: &[RevisionProblem]) -> Result<Vec<PathBuf>, APMError> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let dir = self.root.join(QUARANTINE_DIR_NAME).join(secs.to_string());
+        let mut paths = Vec::new();
+        let mut moved = Vec::new();
+
+        for p in problems {
+            if let Problem::CorruptBlob { blob, .. } = &p.problem {
+                paths.push(self.blobs().path(blob)?);
+            }
+
+            if let Some(revision) = self.get(&p.name).and_then(|r| {
+                return r.revisions.iter().find(|r| r.revision == p.revision);
+            }) {
+                paths.push(self.revision_path(revision));
+                paths.push(self.metadata_path(revision));
+            }
+        }
+
+        for path in paths {
+            if !path.is_file() {
+                continue;
+            }
+
+            let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+            let dest = dir.join(relative);
+
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent).map_err(|e| {
+                    APMErrorType::DirectoryCreationError
+                        .from_error(e)
+                        .with_path(parent)
+                })?;
+            }
+
+            fs::rename(&path, &dest).map_err(|e| {
+                APMErrorType::PackageRemoveError
+                    .from_error(e)
+                    .with_path(&path)
+            })?;
+
+            moved.push(dest);
+        }
+
+        for p in problems {
+            if let Some(record) = self.index.packages.get_mut(&p.name) {
+                record.revisions.retain(|r| r.revision != p.revision);
+
+                if record.latest == Some(p.revision) {
+                    record.latest = None;
+                }
+
+                if record.revisions.is_empty() {
+                    self.index.packages.remove(&p.name);
+                }
+            }
+        }
+
+        self.write_index()?;
+
+        return Ok(moved);
+    }
+
+    fn write_index(&self) -> Result<(), APMError> {
+        let index_path = self.root.join(INDEX_FILE_NAME);
+
+        fs::create_dir_all(&self.root).map_err(|e| {
+            APMErrorType::StoreCreationError
+                .from_error(e)
+                .with_path(&self.root)
+        })?;
+
+        let contents = serde_json::to_vec_pretty(&self.index)
+            .map_err(|e| APMErrorType::StoreIndexWriteError.from_error(e))?;
+
+        write_atomically(&index_path, false, |f| {
+            return f.write_all(&contents).map_err(|e| {
+                APMErrorType::StoreIndexWriteError
+                    .from_error(e)
+                    .with_path(&index_path)
+            });
+        })?;
+
+        return Ok(());
+    }
+}
+
+// Whether the blob still has the contents it is named after.
+fn blob_is_intact(blobs: &BlobStore, blob: &str, size: u64) -> bool {
+    let mut writer = HashingWriter::new(sink());
+    let copied = blobs
+        .open(blob)
+        .ok()
+        .and_then(|mut f| copy(&mut f, &mut writer).ok());
+
+    return copied == Some(size) && to_hex(&writer.finalize()) == blob;
+}
+
+/// Parses a version, allowing the missing parts of versions such as `1` or `1.2` and a leading `v`.
+pub fn parse_version(version: &str) -> Result<Version, APMError> {
+    let trimmed = version.trim();
+    let trimmed = trimmed.strip_prefix(['v', 'V']).unwrap_or(trimmed);
+    let (core, rest) = trimmed.split_at(trimmed.find(['-', '+']).unwrap_or(trimmed.len()));
+    let padding = 2usize.saturating_sub(core.matches('.').count());
+    let full = format!("{}{}{}", core, ".0".repeat(padding), rest);
+
+    return Version::parse(&full).map_err(|e| {
+        APMErrorType::InvalidVersionError
+            .into_apm_error(format!("'{}' is not a valid version: {}", version, e))
+            .with_source(e)
+    });
+}
+
+/// Splits a package reference such as `name`, `name@1.2` or `name@^1.2` into its name and version.
+pub fn parse_package_ref(reference: &str) -> (&str, Option<&str>) {
+    return match reference.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (reference, None),
+    };
+}
+
+pub fn validate_package_name(name: &str) -> Result<(), APMError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+
+    if !valid {
+        return Err(APMErrorType::InvalidPackageNameError.into_apm_error(format!(
+            "'{}' is not a valid package name, names may only contain letters, digits, '_', '-' and '.' and cannot start with '.'",
+            name
+        )));
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    const SAMPLE_ZIP: &str = "test_files/no_hidden.zip";
+    const SAMPLE_HIDDEN_ZIP: &str = "test_files/hidden.zip";
+
+    #[test]
+    fn test_add_find_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = PackageStore::open(dir.path()).unwrap();
+
+        store.add_file("sample", Some("1.0"), SAMPLE_ZIP).unwrap();
+        let rev = store
+            .add_file("sample", Some("1.1"), SAMPLE_HIDDEN_ZIP)
+            .unwrap();
+        assert_eq!(rev.revision, 2);
+        assert!(rev.checksum.is_some());
+
+        // The index should survive re-opening the store.
+        let mut store = PackageStore::open(dir.path()).unwrap();
+        assert_eq!(store.find("sample", None).unwrap().revision, 2);
+        assert_eq!(store.find("sample", Some("1.0")).unwrap().revision, 1);
+        assert!(store.find("sample", Some("2.0")).is_err());
+        assert!(store
+            .revision_path(store.find("sample", None).unwrap())
+            .exists());
+
+        let removed = store.remove("sample", Some("1.1")).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(store.find("sample", None).unwrap().revision, 1);
+
+        store.remove("sample", None).unwrap();
+        assert!(store.get("sample").is_none());
+    }
+
+    #[test]
+    fn test_missing_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("store");
+
+        // Reading a store which does not exist leaves it that way.
+        let mut store = PackageStore::open(&root).unwrap();
+        assert_eq!(store.packages().count(), 0);
+        assert!(store.find("sample", None).is_err());
+        assert!(store.fsck(false).unwrap().problems.is_empty());
+        assert_eq!(
+            store.gc(&GcOptions::default()).unwrap(),
+            GcSummary::default()
+        );
+        assert!(!root.exists());
+
+        store.add_file("sample", None, SAMPLE_ZIP).unwrap();
+        assert!(root.join(INDEX_FILE_NAME).is_file());
+        assert_eq!(
+            PackageStore::open(&root)
+                .unwrap()
+                .find("sample", None)
+                .unwrap()
+                .revision,
+            1
+        );
+    }
+
+    #[test]
+    fn test_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = PackageStore::open(dir.path()).unwrap();
+
+        assert_eq!(parse_version("v1.2").unwrap(), Version::new(1, 2, 0));
+        assert!(parse_version("1.2.x").is_err());
+
+        store.add_file("sample", Some("1.2"), SAMPLE_ZIP).unwrap();
+        store.add_file("sample", Some("2.0"), SAMPLE_ZIP).unwrap();
+        store.add_file("sample", Some("1.3.1"), SAMPLE_ZIP).unwrap();
+
+        // The revision added last is the latest, even though it is not the highest version.
+        assert_eq!(store.find("sample", None).unwrap().revision, 3);
+        assert_eq!(store.find("sample", Some("^1.2")).unwrap().revision, 3);
+        assert_eq!(store.find("sample", Some(">=1.0")).unwrap().revision, 2);
+        assert!(store.find("sample", Some("^3")).is_err());
+
+        assert_eq!(store.set_latest("sample", "2").unwrap().revision, 2);
+        assert_eq!(store.rollback("sample").unwrap().revision, 3);
+        assert_eq!(store.rollback("sample").unwrap().revision, 1);
+        assert!(store.rollback("sample").is_err());
+
+        let store = PackageStore::open(dir.path()).unwrap();
+        assert_eq!(store.find("sample", None).unwrap().revision, 1);
+    }
+
+    #[test]
+    fn test_migrate_v1_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let revision = |n: u32, version: &str| {
+            return json!({
+                "revision": n,
+                "version": version,
+                "file": format!("{}.zip", n),
+                "checksum": null,
+                "size": 0,
+                "added": 0,
+            });
+        };
+        let index = json!({
+            "format_version": 1,
+            "packages": {"sample": {"revisions": [revision(1, "1.0"), revision(2, "weird")]}},
+        });
+        fs::write(dir.path().join(INDEX_FILE_NAME), index.to_string()).unwrap();
+
+        let store = PackageStore::open(dir.path()).unwrap();
+        let record = store.get("sample").unwrap();
+        assert_eq!(record.revisions[0].version, Some(Version::new(1, 0, 0)));
+        assert_eq!(record.revisions[1].version, None);
+        assert_eq!(record.revisions[1].version_string(), "weird");
+        assert_eq!(store.find("sample", Some("1")).unwrap().revision, 1);
+        assert_eq!(store.find("sample", None).unwrap().revision, 2);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = PackageStore::open(dir.path()).unwrap();
+        let details = PackageDetails {
+            description: Some(" Sample package ".to_string()),
+            author: Some(String::new()),
+            tags: vec!["b".to_string(), "a".to_string(), "b".to_string()],
+            ..Default::default()
+        };
+
+        let revision = store
+            .add_package_with_details(
+                "sample",
+                Some("1.0"),
+                &mut Package::open(SAMPLE_HIDDEN_ZIP).unwrap(),
+                details,
+            )
+            .unwrap()
+            .clone();
+        let metadata = store.metadata("sample", &revision).unwrap();
+        assert_eq!(metadata.version, Some(Version::new(1, 0, 0)));
+        assert_eq!(metadata.checksum, revision.checksum);
+        assert_eq!(
+            metadata.details.description.as_deref(),
+            Some("Sample package")
+        );
+        assert_eq!(metadata.details.author, None);
+        assert_eq!(metadata.details.tags, vec!["a", "b"]);
+
+        let mut details = metadata.details;
+        details.tags.push("not valid".to_string());
+        assert!(store.set_details("sample", &revision, details).is_err());
+
+        store.remove("sample", None).unwrap();
+        assert!(!store.metadata_path(&revision).exists());
+    }
+
+    #[test]
+    fn test_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = PackageStore::open(dir.path()).unwrap();
+        let details = |archer_version: &str, tags: &[&str]| {
+            return PackageDetails {
+                archer_version: Some(archer_version.to_string()),
+                tags: tags.iter().map(|t| t.to_string()).collect(),
+                ..Default::default()
+            };
+        };
+
+        for (name, version, path, details) in [
+            ("Finance", "1.0", SAMPLE_ZIP, details("6.9", &["finance"])),
+            (
+                "Finance",
+                "1.2",
+                SAMPLE_HIDDEN_ZIP,
+                details("6.9.1", &["finance", "core"]),
+            ),
+            ("hr", "2.0", SAMPLE_ZIP, details("6.10", &["hr"])),
+        ] {
+            store
+                .add_package_with_details(
+                    name,
+                    Some(version),
+                    &mut Package::open(path).unwrap(),
+                    details,
+                )
+                .unwrap();
+        }
+
+        let found = |query: SearchQuery| {
+            return store
+                .search(&query)
+                .iter()
+                .map(|(name, r)| format!("{}@{}", name, r.version_string()))
+                .collect::<Vec<_>>();
+        };
+
+        assert_eq!(
+            found(SearchQuery::default()),
+            vec!["Finance@1.2.0", "hr@2.0.0"]
+        );
+        assert_eq!(
+            found(SearchQuery {
+                name: Some("fin".to_string()),
+                version: Some(VersionSpec::parse("<1.2").unwrap()),
+                ..Default::default()
+            }),
+            vec!["Finance@1.0.0"]
+        );
+        assert_eq!(
+            found(SearchQuery {
+                archer_version: Some("6.9".to_string()),
+                all_revisions: true,
+                reverse: true,
+                ..Default::default()
+            }),
+            vec!["Finance@1.2.0", "Finance@1.0.0"]
+        );
+        assert_eq!(
+            found(SearchQuery {
+                tags: vec!["finance".to_string(), "core".to_string()],
+                ..Default::default()
+            }),
+            vec!["Finance@1.2.0"]
+        );
+        assert_eq!(
+            found(SearchQuery {
+                order: SearchOrder::Size,
+                ..Default::default()
+            }),
+            vec!["hr@2.0.0", "Finance@1.2.0"]
+        );
+    }
+
+    #[test]
+    fn test_blob_and_zip_revisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = json!({
+            "format_version": 2,
+            "packages": {"sample": {"revisions": [{
+                "revision": 1,
+                "version": "1.0.0",
+                "file": "sample/1.zip",
+                "checksum": null,
+                "size": 0,
+                "added": 0,
+            }]}},
+        });
+        fs::create_dir_all(dir.path().join(PACKAGES_DIR_NAME).join("sample")).unwrap();
+        fs::copy(
+            SAMPLE_HIDDEN_ZIP,
+            dir.path().join(PACKAGES_DIR_NAME).join("sample/1.zip"),
+        )
+        .unwrap();
+        fs::write(dir.path().join(INDEX_FILE_NAME), legacy.to_string()).unwrap();
+
+        let mut store = PackageStore::open(dir.path()).unwrap();
+        let old = store.find("sample", None).unwrap().clone();
+        assert_eq!(old.storage, Storage::Zip);
+
+        let new = store
+            .add_package(
+                "sample",
+                Some("1.1"),
+                &mut store.open_package(&old).unwrap(),
+            )
+            .unwrap()
+            .clone();
+        assert_eq!(new.storage, Storage::Blobs);
+        assert!(store.manifest(&new).unwrap().blobs().all(|b| store
+            .blobs()
+            .path(b)
+            .unwrap()
+            .exists()));
+
+        let out = dir.path().join("out.zip").display().to_string();
+        assert!(store.export(&old, &out, false).unwrap().identical);
+        assert_eq!(
+            fs::read(&out).unwrap(),
+            fs::read(SAMPLE_HIDDEN_ZIP).unwrap()
+        );
+
+        // The sample was not made by apm so it cannot be rebuilt byte for byte, but the contents
+        // and a valid checksum are.
+        assert!(!store.export(&new, &out, false).unwrap().identical);
+        let mut exported = Package::open(&out).unwrap();
+        let mut original = Package::open(SAMPLE_HIDDEN_ZIP).unwrap();
+        assert_eq!(
+            exported
+                .contents()
+                .unwrap()
+                .file_names()
+                .collect::<Vec<_>>(),
+            original
+                .contents()
+                .unwrap()
+                .file_names()
+                .collect::<Vec<_>>()
+        );
+        assert!(matches!(
+            exported.verify().unwrap(),
+            crate::packages::ChecksumStatus::Valid(_)
+        ));
+
+        let index: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.path().join(INDEX_FILE_NAME)).unwrap()).unwrap();
+        assert_eq!(index["format_version"], INDEX_FORMAT_VERSION);
+    }
+
+    #[test]
+    fn test_gc_and_fsck() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = PackageStore::open(dir.path()).unwrap();
+        let revision = store
+            .add_package(
+                "sample",
+                None,
+                &mut Package::open(SAMPLE_HIDDEN_ZIP).unwrap(),
+            )
+            .unwrap()
+            .clone();
+        let (orphan, _) = store.blobs().write(&mut &b"unused"[..]).unwrap();
+        fs::write(dir.path().join(".index.x.tmp"), "partial").unwrap();
+
+        let options = GcOptions {
+            dry_run: false,
+            min_age: Duration::from_secs(0),
+        };
+        let summary = store.gc(&options).unwrap();
+        assert_eq!(summary.removed_blobs, vec![orphan.clone()]);
+        assert_eq!(summary.removed_temp_files.len(), 1);
+        assert!(!store.blobs().path(&orphan).unwrap().exists());
+        assert!(store.fsck(false).unwrap().problems.is_empty());
+
+        let manifest = store.manifest(&revision).unwrap();
+        let blob = manifest.blobs().next().unwrap();
+        fs::write(store.blobs().path(blob).unwrap(), "corrupt").unwrap();
+
+        let summary = store.fsck(true).unwrap();
+        assert!(matches!(
+            summary.problems[0].problem,
+            Problem::CorruptBlob { .. }
+        ));
+        assert!(!summary.quarantined.is_empty());
+        assert!(store.find("sample", None).is_err());
+        assert!(store.fsck(false).unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn test_fsck_recorded_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = PackageStore::open(dir.path()).unwrap();
+        let revision = store
+            .add_package(
+                "sample",
+                None,
+                &mut Package::open(SAMPLE_HIDDEN_ZIP).unwrap(),
+            )
+            .unwrap()
+            .clone();
+        let manifest = store.manifest(&revision).unwrap();
+
+        // The sample is not rebuilt byte for byte, so its `/hidden` entry is recalculated.
+        assert!(!manifest
+            .is_identical(&manifest.assemble(&store.blobs()).unwrap())
+            .unwrap());
+        assert!(store.fsck(false).unwrap().problems.is_empty());
+
+        store.index.packages.get_mut("sample").unwrap().revisions[0].checksum =
+            Some("tampered".to_string());
+
+        let summary = store.fsck(false).unwrap();
+        assert_eq!(
+            summary.problems[0].problem,
+            Problem::RecordedChecksumMismatch {
+                recorded: Some("tampered".to_string()),
+                stored: revision.checksum,
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        assert!(validate_package_name("finance_app-1.2").is_ok());
+        assert!(validate_package_name("").is_err());
+        assert!(validate_package_name("..").is_err());
+        assert!(validate_package_name("a/b").is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use std::cmp::min;
+use std::fmt::Write;
+
+// The largest LCS table which will be built, inputs needing more cells are not diffed.
+const MAX_TABLE_CELLS: usize = 16 * 1024 * 1024;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LineOp {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Computes the edit script turning `old` into `new` using the longest common subsequence of their
+/// lines. Returns `None` when the inputs are too large to compare.
+pub fn diff_lines(old: &[&str], new: &[&str]) -> Option<Vec<LineOp>> {
+    // Lines shared at the start and end are kept out of the table, which is where most of the
+    // work would otherwise go for small edits to large files.
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    if (a.len() + 1).saturating_mul(b.len() + 1) > MAX_TABLE_CELLS {
+        return None;
+    }
+
+    // lengths[i][j] is the length of the LCS of a[i..] and b[j..]
+    let width = b.len() + 1;
+    let mut lengths = vec![0u32; (a.len() + 1) * width];
+
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i * width + j] = if a[i] == b[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut ops = vec![LineOp::Equal; prefix];
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            ops.push(LineOp::Equal);
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            ops.push(LineOp::Delete);
+            i += 1;
+        } else {
+            ops.push(LineOp::Insert);
+            j += 1;
+        }
+    }
+
+    ops.extend((i..a.len()).map(|_| LineOp::Delete));
+    ops.extend((j..b.len()).map(|_| LineOp::Insert));
+    ops.extend((0..suffix).map(|_| LineOp::Equal));
+
+    return Some(ops);
+}
+
+/// Formats the differences between `old` and `new` as a unified diff with `context` lines around
+/// each change. Returns `None` if the texts are the same or too large to compare.
+pub fn unified_diff(
+    old: &str,
+    new: &str,
+    old_name: &str,
+    new_name: &str,
+    context: usize,
+) -> Option<String> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let ops = diff_lines(&old_lines, &new_lines)?;
+
+    if ops.iter().all(|op| *op == LineOp::Equal) {
+        return None;
+    }
+
+    // Line positions in old and new before each op.
+    let mut positions = Vec::with_capacity(ops.len() + 1);
+    let (mut o, mut n) = (0, 0);
+
+    for op in &ops {
+        positions.push((o, n));
+
+        match op {
+            LineOp::Equal => {
+                o += 1;
+                n += 1;
+            }
+            LineOp::Delete => o += 1,
+            LineOp::Insert => n += 1,
+        }
+    }
+
+    positions.push((o, n));
+
+    let mut output = String::new();
+    let _ = writeln!(output, "--- a/{}", old_name);
+    let _ = writeln!(output, "+++ b/{}", new_name);
+
+    let mut start = 0;
+
+    while let Some(first_change) = ops[start..]
+        .iter()
+        .position(|op| *op != LineOp::Equal)
+        .map(|p| p + start)
+    {
+        let hunk_start = first_change.saturating_sub(context);
+        let mut hunk_end = first_change;
+
+        // Extend the hunk while the next change is close enough for the contexts to overlap.
+        loop {
+            while hunk_end < ops.len() && ops[hunk_end] != LineOp::Equal {
+                hunk_end += 1;
+            }
+
+            let next_change = ops[hunk_end..]
+                .iter()
+                .position(|op| *op != LineOp::Equal)
+                .map(|p| p + hunk_end);
+
+            match next_change {
+                Some(next) if next - hunk_end <= context * 2 => hunk_end = next,
+                _ => {
+                    hunk_end = min(hunk_end + context, ops.len());
+                    break;
+                }
+            }
+        }
+
+        let (old_start, new_start) = positions[hunk_start];
+        let (old_end, new_end) = positions[hunk_end];
+
+        let _ = writeln!(
+            output,
+            "@@ -{} +{} @@",
+            hunk_range(old_start, old_end - old_start),
+            hunk_range(new_start, new_end - new_start)
+        );
+
+        for (op, (o, n)) in ops[hunk_start..hunk_end]
+            .iter()
+            .zip(&positions[hunk_start..hunk_end])
+        {
+            let _ = match op {
+                LineOp::Equal => writeln!(output, " {}", old_lines[*o]),
+                LineOp::Delete => writeln!(output, "-{}", old_lines[*o]),
+                LineOp::Insert => writeln!(output, "+{}", new_lines[*n]),
+            };
+        }
+
+        start = hunk_end;
+    }
+
+    return Some(output);
+}
+
+// Ranges are 1-based, an empty range refers to the line before it.
+fn hunk_range(start: usize, len: usize) -> String {
+    if len == 1 {
+        return format!("{}", start + 1);
+    } else if len == 0 {
+        return format!("{},0", start);
+    }
+
+    return format!("{},{}", start + 1, len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        let old = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\n";
+        let new = "one\ntwo\nthree\nfour\nfive\nsix\nseven\nEIGHT\nnine\nten\neleven\n";
+
+        assert_eq!(
+            unified_diff(old, new, "f.txt", "f.txt", 2).unwrap(),
+            "--- a/f.txt\n+++ b/f.txt\n@@ -6,5 +6,6 @@\n six\n seven\n-eight\n+EIGHT\n nine\n ten\n+eleven\n"
+        );
+
+        assert_eq!(unified_diff(old, old, "f.txt", "f.txt", 3), None);
+        assert_eq!(
+            unified_diff("", "a\n", "f", "f", 3).unwrap(),
+            "--- a/f\n+++ b/f\n@@ -0,0 +1 @@\n+a\n"
+        );
+    }
+}
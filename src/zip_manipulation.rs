@@ -1,51 +1,379 @@
+use crate::datetime::civil_from_epoch;
 use crate::error::{APMError, APMErrorType};
 
+use std::env;
 use std::fs::{File, OpenOptions};
-use std::io::{copy, Cursor, Read, Seek, Write};
+use std::io::{copy, Cursor, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::path::{Component, Path, PathBuf};
 
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use walkdir::WalkDir;
-use zip::{write::FileOptions, ZipArchive, ZipWriter};
+use zip::{write::FileOptions, CompressionMethod, DateTime, ZipArchive, ZipWriter};
 
-pub fn compress_directory(
+pub const SOURCE_DATE_EPOCH_VAR: &str = "SOURCE_DATE_EPOCH";
+pub const IGNORE_FILE_NAME: &str = ".apmignore";
+const REPRODUCIBLE_FILE_PERMISSIONS: u32 = 0o644;
+const REPRODUCIBLE_DIR_PERMISSIONS: u32 = 0o755;
+// 1980-01-01 00:00:00 and 2107-12-31 23:59:58, the range a zip timestamp can hold.
+const ZIP_MIN_EPOCH: u64 = 315532800;
+const ZIP_MAX_EPOCH: u64 = 4354819198;
+
+#[derive(Clone, Debug, Default)]
+pub struct CompressOptions {
+    pub track_file_names: bool,
+    /// Gives every entry the same timestamp, permissions and compression settings so the same
+    /// tree always compresses to the same bytes.
+    pub reproducible: bool,
+    /// Seconds since the Unix epoch used as the timestamp of every entry in a reproducible build,
+    /// defaults to the earliest time a zip can store.
+    pub timestamp: Option<u64>,
+    /// Patterns in `.gitignore` syntax for paths to leave out, they take precedence over
+    /// `.apmignore` files.
+    pub exclude: Vec<String>,
+    /// Patterns for paths to keep even when an `.apmignore` file or `exclude` would leave them out.
+    pub include: Vec<String>,
+    pub symlinks: SymlinkPolicy,
+    pub compression: CompressionSettings,
+}
+
+/// How the entries of a package are compressed.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct CompressionSettings {
+    /// Defaults to deflate.
+    pub method: Option<CompressionMethod>,
+    /// Applies to every compressed entry, stored entries have no level.
+    pub level: Option<i32>,
+    /// Methods for files with particular extensions, e.g. storing images which are already
+    /// compressed. Extensions are matched without the dot and ignoring case.
+    pub extension_methods: Vec<(String, CompressionMethod)>,
+}
+
+/// What `compress_directory` does with symlinks.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub enum SymlinkPolicy {
+    #[default]
+    Error,
+    /// Adds the target of the link in its place. Targets must be inside the directory being
+    /// compressed and links which lead back to one of their own parents are an error.
+    Follow,
+    /// Leaves links out, they are listed in `CompressSummary::skipped_symlinks`.
+    Skip,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct CompressSummary {
+    /// The entry names, only tracked when `CompressOptions::track_file_names` is set.
+    pub file_names: Option<Vec<String>>,
+    pub skipped_symlinks: Vec<String>,
+}
+
+// The ignore rules in effect while walking a directory. An `.apmignore` file applies to the
+// directory it is in and everything below it, the deepest file with a matching pattern decides.
+struct IgnoreRules {
+    overrides: Gitignore,
+    // The depth and path relative to the root of each directory with an `.apmignore` file
+    files: Vec<(usize, PathBuf, Gitignore)>,
+}
+
+impl CompressOptions {
+    /// The options files are written with, also used for the checksum entry so a reproducible
+    /// package stays reproducible once its checksum is added.
+    pub fn file_options(&self) -> Result<FileOptions, APMError> {
+        let options = self.compression.apply_default(FileOptions::default());
+
+        if !self.reproducible {
+            return Ok(options);
+        }
+
+        let timestamp = zip_datetime(self.timestamp.unwrap_or(ZIP_MIN_EPOCH))?;
+
+        return Ok(options
+            .last_modified_time(timestamp)
+            .unix_permissions(REPRODUCIBLE_FILE_PERMISSIONS));
+    }
+}
+
+impl CompressionSettings {
+    /// Checks that every method is one Archer can import and that the level suits it.
+    pub fn validate(&self) -> Result<(), APMError> {
+        let methods = self
+            .method
+            .iter()
+            .chain(self.extension_methods.iter().map(|(_, m)| m));
+
+        for method in methods {
+            if !is_archer_compression(*method) {
+                return Err(
+                    APMErrorType::UnsupportedCompressionError.into_apm_error(format!(
+                        "Archer cannot import packages compressed with {}, use stored or deflate",
+                        method
+                    )),
+                );
+            }
+        }
+
+        if let Some(level) = self.level {
+            let compressed = iter::once(self.default_method())
+                .chain(self.extension_methods.iter().map(|(_, m)| *m))
+                .any(|m| m != CompressionMethod::Stored);
+
+            if !compressed {
+                return Err(APMErrorType::UnsupportedCompressionError
+                    .into_apm_error("Stored entries have no compression level".to_string()));
+            }
+
+            // Deflate is the only compressed method Archer imports
+            if !(0..=9).contains(&level) {
+                return Err(
+                    APMErrorType::UnsupportedCompressionError.into_apm_error(format!(
+                        "{} is not a valid deflate level, it must be between 0 and 9",
+                        level
+                    )),
+                );
+            }
+        }
+
+        return Ok(());
+    }
+
+    pub fn default_method(&self) -> CompressionMethod {
+        return self.method.unwrap_or(CompressionMethod::Deflated);
+    }
+
+    /// The method used for the entry called `name`.
+    pub fn method_for(&self, name: &str) -> CompressionMethod {
+        let file_name = name.rsplit('/').next().unwrap_or(name);
+
+        if let Some((_, extension)) = file_name.rsplit_once('.') {
+            for (e, method) in &self.extension_methods {
+                if e.trim_start_matches('.').eq_ignore_ascii_case(extension) {
+                    return *method;
+                }
+            }
+        }
+
+        return self.default_method();
+    }
+
+    /// Sets the method and level for the entry called `name` on `options`.
+    pub fn apply(&self, options: FileOptions, name: &str) -> FileOptions {
+        return self.with_method(options, self.method_for(name));
+    }
+
+    pub fn apply_default(&self, options: FileOptions) -> FileOptions {
+        return self.with_method(options, self.default_method());
+    }
+
+    fn with_method(&self, options: FileOptions, method: CompressionMethod) -> FileOptions {
+        let level = match method {
+            CompressionMethod::Stored => None,
+            _ => self.level,
+        };
+
+        return options.compression_method(method).compression_level(level);
+    }
+}
+
+/// Whether Archer can import entries compressed with `method`.
+pub fn is_archer_compression(method: CompressionMethod) -> bool {
+    return matches!(
+        method,
+        CompressionMethod::Stored | CompressionMethod::Deflated
+    );
+}
+
+impl IgnoreRules {
+    fn new(options: &CompressOptions) -> Result<Self, APMError> {
+        // Every matcher is rooted at "." and given paths relative to its own directory
+        let mut builder = GitignoreBuilder::new(".");
+        let patterns = options
+            .exclude
+            .iter()
+            .cloned()
+            .chain(options.include.iter().map(|p| format!("!{}", p)));
+
+        for pattern in patterns {
+            builder.add_line(None, &pattern).map_err(|e| {
+                APMErrorType::InvalidIgnorePatternError
+                    .into_apm_error(format!("{}: {}", pattern, e))
+                    .with_source(e)
+            })?;
+        }
+
+        let overrides = builder
+            .build()
+            .map_err(|e| APMErrorType::InvalidIgnorePatternError.from_error(e))?;
+
+        return Ok(Self {
+            overrides,
+            files: Vec::new(),
+        });
+    }
+
+    // Loads the `.apmignore` file of the directory at `relative` if there is one.
+    fn enter(&mut self, root: &Path, relative: &Path, depth: usize) -> Result<(), APMError> {
+        let path = root.join(relative).join(IGNORE_FILE_NAME);
+
+        if !path.is_file() {
+            return Ok(());
+        }
+
+        let mut builder = GitignoreBuilder::new(".");
+
+        if let Some(e) = builder.add(&path) {
+            return Err(APMErrorType::InvalidIgnorePatternError
+                .from_error(e)
+                .with_path(&path));
+        }
+
+        let gitignore = builder.build().map_err(|e| {
+            APMErrorType::InvalidIgnorePatternError
+                .from_error(e)
+                .with_path(&path)
+        })?;
+
+        self.files.push((depth, relative.to_path_buf(), gitignore));
+
+        return Ok(());
+    }
+
+    // Drops the rules of directories which the walk has left, `depth` is that of the next entry.
+    fn leave(&mut self, depth: usize) {
+        self.files.retain(|(d, _, _)| *d < depth);
+    }
+
+    fn is_excluded(&self, relative: &Path, is_dir: bool) -> bool {
+        let m = self.overrides.matched(relative, is_dir);
+
+        if !m.is_none() {
+            return m.is_ignore();
+        }
+
+        for (_, dir, gitignore) in self.files.iter().rev() {
+            let path = relative.strip_prefix(dir).unwrap_or(relative);
+            let m = gitignore.matched(path, is_dir);
+
+            if !m.is_none() {
+                return m.is_ignore();
+            }
+        }
+
+        return false;
+    }
+}
+
+/// Compresses the contents of `path` in file name order. Paths excluded by `.apmignore` files or `options` are left
+/// out, as are the `.apmignore` files themselves.
+pub fn compress_directory<W: Write + Seek>(
     path: &str,
-    track_file_names: bool,
-) -> Result<(Vec<u8>, Option<Vec<String>>), APMError> {
-    let mut buffer = Vec::new();
-    let options = FileOptions::default();
-    let mut zip_writer = ZipWriter::new(Cursor::new(&mut buffer));
+    options: &CompressOptions,
+    writer: W,
+) -> Result<CompressSummary, APMError> {
+    options.compression.validate()?;
+
+    let file_options = options.file_options()?;
+    let mut dir_options = file_options;
+
+    if options.reproducible {
+        dir_options = dir_options.unix_permissions(REPRODUCIBLE_DIR_PERMISSIONS);
+    }
+
+    let mut zip_writer = ZipWriter::new(writer);
     let mut file_names = {
-        if track_file_names {
+        if options.track_file_names {
             Some(Vec::new())
         } else {
             None
         }
     };
+    let mut skipped_symlinks = Vec::new();
+    let root = Path::new(path);
+    let follow = options.symlinks == SymlinkPolicy::Follow;
+    let canonical_root = match follow {
+        true => Some(
+            root.canonicalize()
+                .map_err(|e| APMErrorType::FileOpenError.from_error(e).with_path(root))?,
+        ),
+        false => None,
+    };
+    let mut rules = IgnoreRules::new(options)?;
+    let mut entries = WalkDir::new(root)
+        .follow_links(follow)
+        .sort_by_file_name()
+        .into_iter();
 
-    for entry in WalkDir::new(path).into_iter() {
-        let entry = entry.map_err(|e| APMErrorType::WalkdirError.into_apm_error(e.to_string()))?;
+    while let Some(entry) = entries.next() {
+        let entry = entry.map_err(|e| {
+            let path = e.path().map(|p| p.to_path_buf());
+            let error = match e.loop_ancestor() {
+                Some(ancestor) => APMErrorType::SymlinkFoundError.into_apm_error(format!(
+                    "The symlink at {} leads back to {}",
+                    path.as_deref().unwrap_or(root).display(),
+                    ancestor.display()
+                )),
+                None => APMErrorType::WalkdirError.from_error(e),
+            };
 
-        let name = entry.path().display().to_string();
+            return match path {
+                Some(p) => error.with_path(p),
+                None => error,
+            };
+        })?;
 
         // Skip the current directory
-        if name == path {
+        if entry.depth() == 0 {
+            rules.enter(root, Path::new(""), 0)?;
             continue;
         }
 
+        let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        let is_dir = entry.file_type().is_dir();
+
+        rules.leave(entry.depth());
+
+        if entry.file_name() == IGNORE_FILE_NAME || rules.is_excluded(relative, is_dir) {
+            if is_dir {
+                entries.skip_current_dir();
+            }
+
+            continue;
+        }
+
+        let name = relative_entry_name(root, entry.path())?;
+
+        if let Some(canonical_root) = &canonical_root {
+            if entry.path_is_symlink() {
+                check_symlink_target(canonical_root, entry.path())?;
+            }
+        }
+
         if entry.file_type().is_symlink() {
-            return Err(APMErrorType::SymlinkFoundError.into_apm_error(format!(
-                "Found symlink at path {}\nSymlinks cannot be compressed.",
-                entry.file_name().to_str().unwrap_or("PATH_UNKNOWN")
-            )));
+            if options.symlinks == SymlinkPolicy::Skip {
+                skipped_symlinks.push(name);
+                continue;
+            }
+
+            return Err(APMErrorType::SymlinkFoundError
+                .into_apm_error(format!(
+                    "Found symlink at path {}\nSymlinks are only compressed when they are followed or skipped.",
+                    entry.path().display()
+                ))
+                .with_path(entry.path()));
         } else if entry.file_type().is_dir() {
             zip_writer
-                .add_directory(&name, options)
-                .map_err(|e| APMErrorType::ZIPAddDirectoryError.into_apm_error(e.to_string()))?;
+                .add_directory(&name, dir_options)
+                .map_err(|e| APMErrorType::ZIPAddDirectoryError.from_error(e))?;
+
+            rules.enter(root, relative, entry.depth())?;
 
             if let Some(file_names) = &mut file_names {
                 file_names.push(name);
             }
         } else if entry.file_type().is_file() {
-            add_file_to_archive(&mut zip_writer, &name, Some(options))?;
+            let file_options = options.compression.apply(file_options, &name);
+
+            add_file_to_archive(&mut zip_writer, &name, entry.path(), Some(file_options))?;
 
             if let Some(file_names) = &mut file_names {
                 file_names.push(name);
@@ -55,41 +383,704 @@ pub fn compress_directory(
 
     zip_writer
         .finish()
-        .map_err(|e| APMErrorType::ZIPFinishError.into_apm_error(e.to_string()))?;
+        .map_err(|e| APMErrorType::ZIPFinishError.from_error(e))?;
+
+    return Ok(CompressSummary {
+        file_names,
+        skipped_symlinks,
+    });
+}
+
+// Makes sure a followed symlink does not pull files from outside the directory being compressed.
+fn check_symlink_target(canonical_root: &Path, link: &Path) -> Result<(), APMError> {
+    let target = link.canonicalize().map_err(|e| {
+        APMErrorType::SymlinkFoundError
+            .from_error(e)
+            .with_path(link)
+    })?;
+
+    if !target.starts_with(canonical_root) {
+        return Err(APMErrorType::SymlinkFoundError
+            .into_apm_error(format!(
+                "The symlink at {} points to {} which is outside of {}",
+                link.display(),
+                target.display(),
+                canonical_root.display()
+            ))
+            .with_path(link));
+    }
+
+    return Ok(());
+}
+
+/// Reads `SOURCE_DATE_EPOCH`, the conventional way of fixing timestamps in reproducible builds.
+pub fn source_date_epoch() -> Result<Option<u64>, APMError> {
+    return match env::var(SOURCE_DATE_EPOCH_VAR) {
+        Ok(v) => v.trim().parse::<u64>().map(Some).map_err(|e| {
+            APMErrorType::InvalidTimestampError
+                .into_apm_error(format!("{}\n{}={}", e, SOURCE_DATE_EPOCH_VAR, v))
+        }),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(APMErrorType::InvalidTimestampError.from_error(e)),
+    };
+}
 
-    drop(zip_writer);
+/// Converts seconds since the Unix epoch (UTC) into a zip timestamp, clamped to the range a zip
+/// can store.
+pub fn zip_datetime(secs: u64) -> Result<DateTime, APMError> {
+    let (year, month, day, hour, minute, second) =
+        civil_from_epoch(secs.clamp(ZIP_MIN_EPOCH, ZIP_MAX_EPOCH));
 
-    return Ok((buffer, file_names));
+    return DateTime::from_date_and_time(year as u16, month, day, hour, minute, second).map_err(
+        |_| {
+            APMErrorType::InvalidTimestampError
+                .into_apm_error(format!("{} cannot be stored in a zip", secs))
+        },
+    );
+}
+
+/// Builds the name of an archive entry from `path` relative to `root`, always using `/` as the
+/// separator so packages built on Windows match those built elsewhere.
+pub fn relative_entry_name(root: &Path, path: &Path) -> Result<String, APMError> {
+    let relative = path.strip_prefix(root).map_err(|_| {
+        APMErrorType::CannotProcessFileNameError.into_apm_error(format!(
+            "{} is not inside {}",
+            path.display(),
+            root.display()
+        ))
+    })?;
+    let mut components = Vec::new();
+
+    for component in relative.components() {
+        match component {
+            Component::Normal(c) => components.push(c.to_str().ok_or_else(|| {
+                APMErrorType::CannotProcessFileNameError
+                    .into_apm_error(format!("The path {} is not valid UTF-8", path.display()))
+            })?),
+            _ => {
+                return Err(APMErrorType::CannotProcessFileNameError
+                    .into_apm_error(format!("Unexpected component in path {}", path.display())))
+            }
+        }
+    }
+
+    return Ok(components.join("/"));
 }
 
 pub fn read_archive(path: &str) -> Result<ZipArchive<File>, APMError> {
-    let mut f = OpenOptions::new()
+    let f = OpenOptions::new()
         .read(true)
         .write(false)
         .open(path)
-        .map_err(|e| APMErrorType::FileOpenError.into_apm_error(e.to_string()))?;
+        .map_err(|e| APMErrorType::FileOpenError.from_error(e).with_path(path))?;
 
-    return Ok(ZipArchive::new(f)
-        .map_err(|e| APMErrorType::ZIPArchiveOpenError.into_apm_error(e.to_string()))?);
+    return ZipArchive::new(f).map_err(|e| {
+        APMErrorType::ZIPArchiveOpenError
+            .from_error(e)
+            .with_path(path)
+    });
 }
 
-pub fn add_file_to_archive<A: Read + Seek + Write>(
+pub fn add_file_to_archive<A: Seek + Write>(
     archive: &mut ZipWriter<A>,
-    file: &str,
+    name: &str,
+    file: &Path,
     options: Option<FileOptions>,
 ) -> Result<(), APMError> {
-    let options = options.unwrap_or(FileOptions::default());
+    let options = options.unwrap_or_default();
 
-    archive
-        .start_file(file, options)
-        .map_err(|e| APMErrorType::ZIPStartFileError.into_apm_error(e.to_string()))?;
+    let mut f = OpenOptions::new()
+        .read(true)
+        .open(file)
+        .map_err(|e| APMErrorType::FileOpenError.from_error(e).with_path(file))?;
+
+    archive.start_file(name, options).map_err(|e| {
+        APMErrorType::ZIPStartFileError
+            .from_error(e)
+            .with_entry(name)
+    })?;
 
-    let mut f = OpenOptions::new().read(true).open(&file).map_err(|e| {
-        APMErrorType::FileOpenError.into_apm_error(format!("{}\nFile:{}", e.to_string(), file))
+    copy(&mut f, archive).map_err(|e| {
+        APMErrorType::ZIPFileCopyError
+            .from_error(e)
+            .with_path(file)
+            .with_entry(name)
     })?;
 
-    copy(&mut f, archive)
-        .map_err(|e| APMErrorType::ZIPFileCopyError.into_apm_error(e.to_string()))?;
+    return Ok(());
+}
+
+const EOCD_SIGNATURE: u32 = 0x06054b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const EOCD_LEN: usize = 22;
+const CENTRAL_HEADER_LEN: usize = 46;
+const ZIP64_LOCATOR_LEN: usize = 20;
+
+pub struct AppendedEntryLayout {
+    /// The number of bytes at the start of the archive which are unchanged by the removal.
+    pub prefix_len: u64,
+    /// The central directory and end record which follow the prefix once the entry is removed.
+    pub tail: Vec<u8>,
+    /// The same with the general purpose flags of each central record restored from its local
+    /// header, for entries appended with `ZipWriter::new_append` (as older versions of apm did)
+    /// which does not always preserve them. `None` when no flags differ.
+    pub legacy_tail: Option<Vec<u8>>,
+}
+
+// The central directory of an archive without ZIP64 records.
+struct CentralDirectory {
+    offset: u64,
+    entry_count: usize,
+    records: Vec<u8>,
+    /// The end of central directory record, including the archive comment.
+    end_record: Vec<u8>,
+    /// Whether nothing follows the end record.
+    at_end: bool,
+}
+
+impl CentralDirectory {
+    /// The end record for a central directory of `entry_count` entries, `records_len` bytes long
+    /// and starting at `offset`, keeping the disk numbers and comment of this one.
+    fn end_record_for(&self, entry_count: usize, records_len: usize, offset: u64) -> Vec<u8> {
+        let mut end_record = Vec::with_capacity(self.end_record.len());
+
+        end_record.extend_from_slice(&self.end_record[..8]);
+        end_record.extend_from_slice(&(entry_count as u16).to_le_bytes());
+        end_record.extend_from_slice(&(entry_count as u16).to_le_bytes());
+        end_record.extend_from_slice(&(records_len as u32).to_le_bytes());
+        end_record.extend_from_slice(&(offset as u32).to_le_bytes());
+        end_record.extend_from_slice(&self.end_record[20..]);
+
+        return end_record;
+    }
+}
+
+/// Works out how to rebuild the archive as it was before `name` was appended to it.
+///
+/// Appending an entry (as `append_entry` does) keeps every earlier byte intact up to the old
+/// central directory, so when `name` is the last entry in the file the original bytes are the
+/// archive up to its local header followed by the central directory without it and a recalculated
+/// end record. Only the central directory is read, so this is cheap regardless of the size of the
+/// archive.
+///
+/// Returns `None` when `name` is not the last entry or the archive cannot be handled (e.g. ZIP64).
+pub fn appended_entry_layout<R: Read + Seek>(
+    reader: &mut R,
+    name: &str,
+) -> Result<Option<AppendedEntryLayout>, APMError> {
+    let cd = match read_central_directory(reader)? {
+        Some(cd) => cd,
+        None => return Ok(None),
+    };
+    let mut records = Vec::with_capacity(cd.entry_count);
+    let mut removed_offset = None;
+    let mut last_offset = 0;
+    let mut pos = 0;
+
+    for _ in 0..cd.entry_count {
+        let header = match parse_central_header(&cd.records, pos) {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+
+        last_offset = last_offset.max(header.local_offset);
+
+        if header.name == name.as_bytes() {
+            removed_offset = Some(header.local_offset);
+        } else {
+            records.push((pos, header.len, header.local_offset));
+        }
+
+        pos += header.len;
+    }
+
+    let removed_offset = match removed_offset {
+        Some(o) if o == last_offset => o,
+        _ => return Ok(None),
+    };
+
+    let mut tail = Vec::with_capacity(pos + cd.end_record.len());
+    let mut legacy_tail = Vec::with_capacity(pos + cd.end_record.len());
+    let mut flags_differ = false;
+
+    for (record_start, record_len, local_offset) in records {
+        let local_header = read_at(reader, local_offset, 8)?;
+
+        if read_u32(&local_header, 0) != Some(LOCAL_HEADER_SIGNATURE) {
+            return Ok(None);
+        }
+
+        let record = &cd.records[record_start..record_start + record_len];
+
+        tail.extend_from_slice(record);
+        legacy_tail.extend_from_slice(&record[..8]);
+        legacy_tail.extend_from_slice(&local_header[6..8]);
+        legacy_tail.extend_from_slice(&record[10..]);
+        flags_differ |= record[8..10] != local_header[6..8];
+    }
+
+    let end_record = cd.end_record_for(cd.entry_count - 1, tail.len(), removed_offset);
+
+    tail.extend_from_slice(&end_record);
+    legacy_tail.extend_from_slice(&end_record);
+
+    return Ok(Some(AppendedEntryLayout {
+        prefix_len: removed_offset,
+        tail,
+        legacy_tail: match flags_differ {
+            true => Some(legacy_tail),
+            false => None,
+        },
+    }));
+}
+
+/// Appends an entry named `name` containing `contents` to the archive in `file`. Unlike
+/// `ZipWriter::new_append`, which rewrites the central directory, the existing central records are
+/// copied byte for byte and only the end record is recalculated, so `appended_entry_layout` can
+/// restore the archive exactly whichever program wrote it.
+///
+/// Returns `false` without changing anything when the archive cannot be handled (e.g. ZIP64).
+pub fn append_entry<F: Read + Write + Seek>(
+    file: &mut F,
+    name: &str,
+    contents: &[u8],
+    options: FileOptions,
+) -> Result<bool, APMError> {
+    let cd = match read_central_directory(file)? {
+        Some(cd) if cd.at_end => cd,
+        _ => return Ok(false),
+    };
+
+    // The zip crate writes the new entry on its own and its records are moved into place.
+    let mut single = Cursor::new(Vec::new());
+    {
+        let mut zip_writer = ZipWriter::new(&mut single);
+
+        zip_writer.start_file(name, options).map_err(|e| {
+            APMErrorType::ZIPStartFileError
+                .from_error(e)
+                .with_entry(name)
+        })?;
+        zip_writer.write_all(contents).map_err(|e| {
+            APMErrorType::ZIPFileWriteError
+                .from_error(e)
+                .with_entry(name)
+        })?;
+        zip_writer
+            .finish()
+            .map_err(|e| APMErrorType::ZIPFinishError.from_error(e))?;
+    }
+
+    let single = single.into_inner();
+    let entry_cd = match read_central_directory(&mut Cursor::new(&single))? {
+        Some(entry_cd) if entry_cd.entry_count == 1 => entry_cd,
+        _ => return Ok(false),
+    };
+    let local_entry = &single[..entry_cd.offset as usize];
+    let new_offset = cd.offset + local_entry.len() as u64;
+
+    if cd.entry_count + 1 >= u16::MAX as usize || new_offset >= u32::MAX as u64 {
+        return Ok(false);
+    }
+
+    let mut record = entry_cd.records;
+    record[42..46].copy_from_slice(&(cd.offset as u32).to_le_bytes());
+
+    let end_record = cd.end_record_for(
+        cd.entry_count + 1,
+        cd.records.len() + record.len(),
+        new_offset,
+    );
+
+    file.seek(SeekFrom::Start(cd.offset))
+        .and_then(|_| file.write_all(local_entry))
+        .and_then(|_| file.write_all(&cd.records))
+        .and_then(|_| file.write_all(&record))
+        .and_then(|_| file.write_all(&end_record))
+        .map_err(|e| APMErrorType::ZIPModificationError.from_error(e))?;
+
+    return Ok(true);
+}
+
+fn read_central_directory<R: Read + Seek>(
+    reader: &mut R,
+) -> Result<Option<CentralDirectory>, APMError> {
+    let len = reader
+        .seek(SeekFrom::End(0))
+        .map_err(|e| APMErrorType::ZIPArchiveReadError.from_error(e))?;
+    let search_len = len.min((ZIP64_LOCATOR_LEN + EOCD_LEN + u16::MAX as usize) as u64);
+    let search = read_at(reader, len - search_len, search_len as usize)?;
+
+    let eocd_start = match find_eocd(&search) {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    let eocd = &search[eocd_start..];
+    let eocd_offset = len - search_len + eocd_start as u64;
+
+    let (disk_entry_count, entry_count, cd_offset, comment_len) = match (
+        read_u16(eocd, 8),
+        read_u16(eocd, 10),
+        read_u32(eocd, 16),
+        read_u16(eocd, 20),
+    ) {
+        (Some(d), Some(n), Some(o), Some(c)) => (d, n as usize, o as u64, c as usize),
+        _ => return Ok(None),
+    };
+
+    if disk_entry_count as usize != entry_count
+        || entry_count == 0xFFFF
+        || cd_offset == 0xFFFFFFFF
+        || cd_offset > eocd_offset
+    {
+        return Ok(None);
+    }
+
+    // ZIP64 archives keep the real sizes and offsets in records before the end record.
+    if eocd_start >= ZIP64_LOCATOR_LEN
+        && read_u32(&search, eocd_start - ZIP64_LOCATOR_LEN) == Some(ZIP64_LOCATOR_SIGNATURE)
+    {
+        return Ok(None);
+    }
+
+    let end_record = match eocd.get(..EOCD_LEN + comment_len) {
+        Some(e) => e.to_vec(),
+        None => return Ok(None),
+    };
+
+    return Ok(Some(CentralDirectory {
+        offset: cd_offset,
+        entry_count,
+        records: read_at(reader, cd_offset, (eocd_offset - cd_offset) as usize)?,
+        at_end: eocd.len() == end_record.len(),
+        end_record,
+    }));
+}
 
-    todo!();
+struct CentralHeader<'a> {
+    name: &'a [u8],
+    len: usize,
+    local_offset: u64,
+}
+
+fn parse_central_header(central_directory: &[u8], pos: usize) -> Option<CentralHeader<'_>> {
+    if read_u32(central_directory, pos)? != CENTRAL_HEADER_SIGNATURE {
+        return None;
+    }
+
+    let name_len = read_u16(central_directory, pos + 28)? as usize;
+    let extra_len = read_u16(central_directory, pos + 30)? as usize;
+    let comment_len = read_u16(central_directory, pos + 32)? as usize;
+    let name_start = pos + CENTRAL_HEADER_LEN;
+
+    return Some(CentralHeader {
+        name: central_directory.get(name_start..name_start + name_len)?,
+        len: CENTRAL_HEADER_LEN + name_len + extra_len + comment_len,
+        local_offset: read_u32(central_directory, pos + 42)? as u64,
+    });
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> Result<Vec<u8>, APMError> {
+    let mut buf = vec![0; len];
+
+    reader
+        .seek(SeekFrom::Start(offset))
+        .and_then(|_| reader.read_exact(&mut buf))
+        .map_err(|e| APMErrorType::ZIPArchiveReadError.from_error(e))?;
+
+    return Ok(buf);
+}
+
+fn find_eocd(zip_bytes: &[u8]) -> Option<usize> {
+    if zip_bytes.len() < EOCD_LEN {
+        return None;
+    }
+
+    let lowest = zip_bytes.len().saturating_sub(EOCD_LEN + u16::MAX as usize);
+
+    return (lowest..=zip_bytes.len() - EOCD_LEN)
+        .rev()
+        .find(|&i| read_u32(zip_bytes, i) == Some(EOCD_SIGNATURE));
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    return Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ));
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    return Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const SAMPLE_DIR: &str = "test_files/test_dir";
+
+    #[test]
+    fn test_compress_directory() {
+        let mut zip_bytes = Cursor::new(Vec::new());
+        let options = CompressOptions {
+            track_file_names: true,
+            ..Default::default()
+        };
+        let file_names = compress_directory(SAMPLE_DIR, &options, &mut zip_bytes)
+            .unwrap()
+            .file_names;
+        let archive = ZipArchive::new(zip_bytes).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort();
+
+        assert_eq!(
+            names,
+            vec![
+                "next_level/",
+                "next_level/sample_2.txt",
+                "out.txt",
+                "sample.txt"
+            ]
+        );
+
+        let mut file_names = file_names.unwrap();
+        file_names.sort();
+        assert_eq!(
+            file_names,
+            vec![
+                "next_level",
+                "next_level/sample_2.txt",
+                "out.txt",
+                "sample.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ignore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+
+        for (name, contents) in [
+            (".apmignore", "*.swp\n/build/\n"),
+            (".git/config", ""),
+            ("app.xml", ""),
+            ("app.xml.swp", ""),
+            ("build/out.o", ""),
+            ("notes.txt", ""),
+            ("sub/.apmignore", "*.log\n!keep.log\n"),
+            ("sub/build/data.txt", ""),
+            ("sub/debug.log", ""),
+            ("sub/keep.log", ""),
+        ] {
+            let path = root.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+
+        let options = CompressOptions {
+            track_file_names: true,
+            exclude: vec![".git/".to_string(), "notes.txt".to_string()],
+            include: vec!["app.xml.swp".to_string()],
+            ..Default::default()
+        };
+        let summary =
+            compress_directory(root.to_str().unwrap(), &options, Cursor::new(Vec::new())).unwrap();
+
+        assert_eq!(
+            summary.file_names.unwrap(),
+            vec![
+                "app.xml",
+                "app.xml.swp",
+                "sub",
+                "sub/build",
+                "sub/build/data.txt",
+                "sub/keep.log"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policies() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("package");
+        std::fs::create_dir_all(root.join("shared")).unwrap();
+        std::fs::write(root.join("shared/asset.txt"), "asset").unwrap();
+        symlink("shared/asset.txt", root.join("link.txt")).unwrap();
+        symlink("shared", root.join("linked")).unwrap();
+
+        let compress = |symlinks| {
+            let options = CompressOptions {
+                track_file_names: true,
+                symlinks,
+                ..Default::default()
+            };
+
+            return compress_directory(root.to_str().unwrap(), &options, Cursor::new(Vec::new()));
+        };
+
+        let e = compress(SymlinkPolicy::Error).unwrap_err();
+        assert_eq!(e.error_type(), APMErrorType::SymlinkFoundError);
+
+        let summary = compress(SymlinkPolicy::Skip).unwrap();
+        assert_eq!(
+            summary.file_names.unwrap(),
+            vec!["shared", "shared/asset.txt"]
+        );
+        assert_eq!(summary.skipped_symlinks, vec!["link.txt", "linked"]);
+
+        let summary = compress(SymlinkPolicy::Follow).unwrap();
+        assert_eq!(
+            summary.file_names.unwrap(),
+            vec![
+                "link.txt",
+                "linked",
+                "linked/asset.txt",
+                "shared",
+                "shared/asset.txt"
+            ]
+        );
+
+        // Links may not leave the package or lead back into their own parents.
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        symlink("../secret.txt", root.join("secret.txt")).unwrap();
+        let e = compress(SymlinkPolicy::Follow).unwrap_err();
+        assert_eq!(e.error_type(), APMErrorType::SymlinkFoundError);
+        std::fs::remove_file(root.join("secret.txt")).unwrap();
+
+        symlink("..", root.join("shared/parent")).unwrap();
+        let e = compress(SymlinkPolicy::Follow).unwrap_err();
+        assert_eq!(e.error_type(), APMErrorType::SymlinkFoundError);
+    }
+
+    #[test]
+    fn test_compression_settings() {
+        let settings = CompressionSettings {
+            method: Some(CompressionMethod::Deflated),
+            level: Some(9),
+            extension_methods: vec![("TXT".to_string(), CompressionMethod::Stored)],
+        };
+        settings.validate().unwrap();
+
+        let options = CompressOptions {
+            compression: settings,
+            ..Default::default()
+        };
+        let mut zip_bytes = Cursor::new(Vec::new());
+        compress_directory(SAMPLE_DIR, &options, &mut zip_bytes).unwrap();
+
+        let mut archive = ZipArchive::new(zip_bytes).unwrap();
+        for i in 0..archive.len() {
+            let f = archive.by_index(i).unwrap();
+            if f.is_file() {
+                assert_eq!(f.compression(), CompressionMethod::Stored, "{}", f.name());
+            }
+        }
+
+        for invalid in [
+            CompressionSettings {
+                method: Some(CompressionMethod::Zstd),
+                ..Default::default()
+            },
+            CompressionSettings {
+                extension_methods: vec![("png".to_string(), CompressionMethod::Bzip2)],
+                ..Default::default()
+            },
+            CompressionSettings {
+                level: Some(10),
+                ..Default::default()
+            },
+            CompressionSettings {
+                method: Some(CompressionMethod::Stored),
+                level: Some(1),
+                ..Default::default()
+            },
+        ] {
+            assert_eq!(
+                invalid.validate().unwrap_err().error_type(),
+                APMErrorType::UnsupportedCompressionError
+            );
+        }
+    }
+
+    #[test]
+    fn test_compressed_contents() {
+        let mut zip_bytes = Cursor::new(Vec::new());
+        compress_directory(SAMPLE_DIR, &CompressOptions::default(), &mut zip_bytes).unwrap();
+        let mut archive = ZipArchive::new(zip_bytes).unwrap();
+        let mut contents = String::new();
+
+        archive
+            .by_name("out.txt")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+
+        assert_eq!(
+            contents,
+            std::fs::read_to_string(Path::new(SAMPLE_DIR).join("out.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reproducible() {
+        let options = CompressOptions {
+            reproducible: true,
+            timestamp: Some(1650000000),
+            ..Default::default()
+        };
+        let mut first = Cursor::new(Vec::new());
+        let mut second = Cursor::new(Vec::new());
+
+        compress_directory(SAMPLE_DIR, &options, &mut first).unwrap();
+        compress_directory(SAMPLE_DIR, &options, &mut second).unwrap();
+        assert_eq!(first.get_ref(), second.get_ref());
+
+        let mut archive = ZipArchive::new(first).unwrap();
+        let names: Vec<String> = (0..archive.len())
+            .map(|i| archive.by_index(i).unwrap().name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "next_level/",
+                "next_level/sample_2.txt",
+                "out.txt",
+                "sample.txt"
+            ]
+        );
+
+        let f = archive.by_name("out.txt").unwrap();
+        let modified = f.last_modified();
+        assert_eq!(
+            (modified.year(), modified.month(), modified.day()),
+            (2022, 4, 15)
+        );
+        assert_eq!(
+            (modified.hour(), modified.minute(), modified.second()),
+            (5, 20, 0)
+        );
+        assert_eq!(f.unix_mode().map(|m| m & 0o777), Some(0o644));
+    }
+
+    #[test]
+    fn test_zip_datetime_clamped() {
+        let earliest = zip_datetime(0).unwrap();
+        assert_eq!(
+            (earliest.year(), earliest.month(), earliest.day()),
+            (1980, 1, 1)
+        );
+
+        let latest = zip_datetime(u64::MAX).unwrap();
+        assert_eq!(
+            (latest.year(), latest.month(), latest.day()),
+            (2107, 12, 31)
+        );
+    }
 }